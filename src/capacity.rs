@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::download;
+use crate::m3u::Item;
+use crate::paths::{self, PathOptions};
+use crate::prune;
+
+const UNITS: [(&str, u64); 4] = [
+    ("T", 1 << 40),
+    ("G", 1 << 30),
+    ("M", 1 << 20),
+    ("K", 1 << 10),
+];
+
+#[derive(Debug)]
+pub struct NotEnoughSpace {
+    pub path: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for NotEnoughSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "not enough space on {:?}: {} needed but only {} available, free {} or set a budget",
+            self.path,
+            format_size(self.needed),
+            format_size(self.available),
+            format_size(self.needed - self.available)
+        )
+    }
+}
+
+pub struct Fit {
+    pub tracks: Vec<Item>,
+    pub skipped: Vec<(Item, u64)>,
+}
+
+#[derive(Debug)]
+pub struct Budget {
+    root_path: PathBuf,
+    remaining: u64,
+    limited: bool,
+}
+
+impl Budget {
+    pub fn measure(root_path: &Path, budget: Option<u64>) -> io::Result<Budget> {
+        let available = available_space(root_path)?;
+        let remaining = match budget {
+            Some(budget) => budget.saturating_sub(media_size(root_path)?).min(available),
+            None => available,
+        };
+        Ok(Budget {
+            root_path: root_path.to_path_buf(),
+            remaining,
+            limited: budget.is_some(),
+        })
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    pub fn fit(
+        &mut self,
+        tracks: Vec<Item>,
+        path_options: &PathOptions,
+    ) -> Result<Fit, NotEnoughSpace> {
+        let sized: Vec<(Item, Option<u64>)> = tracks
+            .into_iter()
+            .map(|track| {
+                let size =
+                    download::remaining_size(&track, &self.root_path, path_options).map(|size| {
+                        size.saturating_sub(replaced_size(&track, &self.root_path, path_options))
+                    });
+                (track, size)
+            })
+            .collect();
+        let needed: u64 = sized.iter().filter_map(|(_, size)| *size).sum();
+        if !self.limited && needed > self.remaining {
+            return Err(NotEnoughSpace {
+                path: self.root_path.clone(),
+                needed,
+                available: self.remaining,
+            });
+        }
+
+        let mut fit = Fit {
+            tracks: vec![],
+            skipped: vec![],
+        };
+        for (track, size) in sized {
+            match size {
+                Some(size) if size > self.remaining => fit.skipped.push((track, size)),
+                Some(size) => {
+                    self.remaining -= size;
+                    fit.tracks.push(track);
+                }
+                None => fit.tracks.push(track),
+            }
+        }
+        Ok(fit)
+    }
+}
+
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size {}, expected a number like 500M or 28G", value);
+    let value = value.trim().to_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, multiplier) = UNITS
+        .iter()
+        .find_map(|(unit, multiplier)| value.strip_suffix(unit).map(|number| (number, *multiplier)))
+        .unwrap_or((value, 1));
+    match number.trim().parse::<f64>() {
+        Ok(number) if number >= 0.0 => Ok((number * multiplier as f64) as u64),
+        _ => Err(invalid()),
+    }
+}
+
+pub fn format_size(size: u64) -> String {
+    match UNITS.iter().find(|(_, multiplier)| size >= *multiplier) {
+        Some((unit, multiplier)) => format!("{:.1} {}B", size as f64 / *multiplier as f64, unit),
+        None => format!("{} B", size),
+    }
+}
+
+fn replaced_size(track: &Item, root_path: &Path, path_options: &PathOptions) -> u64 {
+    fs::metadata(track.local_path(root_path, path_options))
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+fn available_space(root_path: &Path) -> io::Result<u64> {
+    let existing = root_path
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("."));
+    fs4::available_space(existing)
+}
+
+fn media_size(root_path: &Path) -> io::Result<u64> {
+    if !root_path.exists() {
+        return Ok(0);
+    }
+    let mut size = 0;
+    for file in paths::walk(root_path)? {
+        if prune::is_media(&file) {
+            size += fs::metadata(&file)?.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::m3u::TrackData;
+    use crate::test_support::TempDir;
+
+    fn track(path: &str, size: u64) -> Item {
+        Item::new(path.to_string(), vec![TrackData::Size(size)])
+    }
+
+    fn budget(root: &TempDir, remaining: u64, limited: bool) -> Budget {
+        Budget {
+            root_path: root.path().to_path_buf(),
+            remaining,
+            limited,
+        }
+    }
+
+    #[test]
+    fn parses_sizes_with_units() {
+        assert_eq!(Ok(500), parse_size("500"));
+        assert_eq!(Ok(500), parse_size("500B"));
+        assert_eq!(Ok(2 << 10), parse_size("2K"));
+        assert_eq!(Ok(500 << 20), parse_size("500M"));
+        assert_eq!(Ok(28 << 30), parse_size("28GB"));
+        assert_eq!(Ok(1 << 40), parse_size("1T"));
+    }
+
+    #[test]
+    fn parses_sizes_case_insensitively_with_spaces() {
+        assert_eq!(Ok(28 << 30), parse_size(" 28 gb "));
+        assert_eq!(Ok(500 << 20), parse_size("500m"));
+    }
+
+    #[test]
+    fn parses_decimal_sizes() {
+        assert_eq!(Ok(3 << 29), parse_size("1.5G"));
+        assert_eq!(Ok(512), parse_size("0.5K"));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1G").is_err());
+        assert!(parse_size("12 apples").is_err());
+    }
+
+    #[test]
+    fn skips_tracks_that_do_not_fit_in_a_budget() {
+        let root = TempDir::new();
+        let mut budget = budget(&root, 500, true);
+
+        let fit = budget
+            .fit(
+                vec![
+                    track("a.mp3", 300),
+                    track("b.mp3", 300),
+                    track("c.mp3", 200),
+                ],
+                &PathOptions::default(),
+            )
+            .unwrap();
+
+        let paths: Vec<&str> = fit.tracks.iter().map(|track| track.path.as_str()).collect();
+        assert_eq!(vec!["a.mp3", "c.mp3"], paths);
+        assert_eq!(1, fit.skipped.len());
+        assert_eq!(
+            ("b.mp3", 300),
+            (fit.skipped[0].0.path.as_str(), fit.skipped[0].1)
+        );
+        assert_eq!(0, budget.remaining());
+    }
+
+    #[test]
+    fn rejects_tracks_that_do_not_fit_in_the_free_space() {
+        let root = TempDir::new();
+        let mut budget = budget(&root, 500, false);
+
+        let result = budget.fit(
+            vec![track("a.mp3", 300), track("b.mp3", 300)],
+            &PathOptions::default(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(NotEnoughSpace {
+                needed: 600,
+                available: 500,
+                ..
+            })
+        ));
+        assert_eq!(500, budget.remaining());
+    }
+
+    #[test]
+    fn only_counts_the_growth_of_replaced_tracks() {
+        let root = TempDir::new();
+        root.file("a.mp3", &[0; 250]);
+        root.file("b.mp3.part", &[0; 100]);
+        let mut budget = budget(&root, 100, true);
+
+        let fit = budget
+            .fit(
+                vec![track("a.mp3", 300), track("b.mp3", 150)],
+                &PathOptions::default(),
+            )
+            .unwrap();
+
+        assert_eq!(2, fit.tracks.len());
+        assert_eq!(0, budget.remaining());
+    }
+}
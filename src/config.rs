@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use confy::ConfyError;
+use serde::{Deserialize, Serialize};
+
+use crate::capacity;
+use crate::rewrite::RewriteRule;
+use crate::template::PathTemplate;
+
+const APP_NAME: &str = "plexm3u";
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    pub client_identifier: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub server: Option<String>,
+    pub token: Option<String>,
+    pub server_token: Option<String>,
+    pub rewrite_from: Option<String>,
+    pub rewrite_to: Option<String>,
+    pub path: Option<String>,
+    pub template: Option<String>,
+    pub budget: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub playlists: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrites: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Confy(ConfyError),
+    UnknownKey(String),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Confy(error) => write!(f, "{}", error),
+            ConfigError::UnknownKey(key) => write!(
+                f,
+                "unknown key {}, expected one of: {}",
+                key,
+                Profile::KEYS.join(", ")
+            ),
+            ConfigError::InvalidValue(key, message) => write!(f, "invalid {}: {}", key, message),
+        }
+    }
+}
+
+impl From<ConfyError> for ConfigError {
+    fn from(error: ConfyError) -> Self {
+        ConfigError::Confy(error)
+    }
+}
+
+pub struct ConfigFile {
+    path: Option<PathBuf>,
+    pub dry_run: bool,
+}
+
+impl ConfigFile {
+    pub fn new(path: Option<String>, dry_run: bool) -> ConfigFile {
+        ConfigFile {
+            path: path.map(PathBuf::from),
+            dry_run,
+        }
+    }
+
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let path = self.location()?;
+        if path.exists() {
+            Ok(confy::load_path(path)?)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    /// Does nothing with `--dry-run`.
+    pub fn store(&self, config: &Config) -> Result<(), ConfigError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        store_private(&self.location()?, config)
+    }
+
+    pub fn location(&self) -> Result<PathBuf, ConfigError> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => Ok(confy::get_configuration_file_path(APP_NAME, None)?),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn store_private(path: &Path, config: &Config) -> Result<(), ConfigError> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(confy::store_path_perms(
+        path,
+        config,
+        Permissions::from_mode(0o600),
+    )?)
+}
+
+#[cfg(not(unix))]
+fn store_private(path: &Path, config: &Config) -> Result<(), ConfigError> {
+    Ok(confy::store_path(path, config)?)
+}
+
+impl Config {
+    pub fn profile(&self, name: &str) -> Profile {
+        self.profiles.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn profile_mut(&mut self, name: &str) -> &mut Profile {
+        self.profiles.entry(name.to_string()).or_default()
+    }
+
+    pub fn client_identifier(&mut self) -> String {
+        self.client_identifier
+            .get_or_insert_with(|| {
+                let random = RandomState::new().hash_one(SystemTime::now());
+                format!("{}-{:016x}", APP_NAME, random)
+            })
+            .clone()
+    }
+}
+
+impl Profile {
+    pub const KEYS: [&str; 10] = [
+        "server",
+        "token",
+        "server-token",
+        "rewrite-from",
+        "rewrite-to",
+        "path",
+        "template",
+        "budget",
+        "playlists",
+        "rewrites",
+    ];
+
+    pub fn set(&mut self, key: &str, values: Vec<String>) -> Result<(), ConfigError> {
+        let values: Vec<String> = values
+            .into_iter()
+            .filter(|value| !value.is_empty())
+            .collect();
+        if key == "rewrites" {
+            for rule in values.iter() {
+                rule.parse::<RewriteRule>()
+                    .map_err(|message| ConfigError::InvalidValue(key.to_string(), message))?;
+            }
+            self.rewrites = values;
+            return Ok(());
+        }
+        if values.len() > 1 {
+            return Err(ConfigError::InvalidValue(
+                key.to_string(),
+                String::from("expected a single value"),
+            ));
+        }
+        let value = values.into_iter().next();
+        match key {
+            "server" => self.server = value,
+            "token" => self.token = value,
+            "server-token" => self.server_token = value,
+            "rewrite-from" => self.rewrite_from = value,
+            "rewrite-to" => self.rewrite_to = value,
+            "path" => self.path = value,
+            "template" => {
+                if let Some(template) = &value {
+                    template
+                        .parse::<PathTemplate>()
+                        .map_err(|message| ConfigError::InvalidValue(key.to_string(), message))?;
+                }
+                self.template = value
+            }
+            "budget" => {
+                if let Some(budget) = &value {
+                    capacity::parse_size(budget)
+                        .map_err(|message| ConfigError::InvalidValue(key.to_string(), message))?;
+                }
+                self.budget = value
+            }
+            "playlists" => {
+                self.playlists = value
+                    .map(|value| {
+                        value
+                            .split(',')
+                            .map(|rating_key| rating_key.trim().to_string())
+                            .filter(|rating_key| !rating_key.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            }
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn server_token(&self) -> Option<String> {
+        self.server_token.clone().or(self.token.clone())
+    }
+
+    pub fn informations(&self) -> Vec<(&str, Option<String>)> {
+        vec![
+            ("server", self.server.clone()),
+            (
+                "token",
+                self.token.as_ref().map(|_| "[REDACTED]".to_string()),
+            ),
+            (
+                "server-token",
+                self.server_token.as_ref().map(|_| "[REDACTED]".to_string()),
+            ),
+            ("rewrite-from", self.rewrite_from.clone()),
+            ("rewrite-to", self.rewrite_to.clone()),
+            ("path", self.path.clone()),
+            ("template", self.template.clone()),
+            ("budget", self.budget.clone()),
+            (
+                "playlists",
+                Some(self.playlists.join(",")).filter(|playlists| !playlists.is_empty()),
+            ),
+            (
+                "rewrites",
+                Some(format!("{:?}", self.rewrites)).filter(|_| !self.rewrites.is_empty()),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn sets_and_clears_profile_keys() {
+        let mut profile = Profile::default();
+
+        profile
+            .set("server", values(&["http://plex:32400"]))
+            .unwrap();
+        profile.set("playlists", values(&["12, 34,"])).unwrap();
+        profile.set("token", vec![]).unwrap();
+
+        assert_eq!(Some(String::from("http://plex:32400")), profile.server);
+        assert_eq!(vec!["12", "34"], profile.playlists);
+        assert_eq!(None, profile.token);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let mut profile = Profile::default();
+
+        let result = profile.set("servre", values(&["http://plex:32400"]));
+
+        assert!(matches!(result, Err(ConfigError::UnknownKey(key)) if key == "servre"));
+    }
+
+    #[test]
+    fn stores_and_loads_profiles() {
+        let directory = TempDir::new();
+        let config_file = ConfigFile::new(
+            Some(
+                directory
+                    .path()
+                    .join("config.toml")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            false,
+        );
+        let mut config = Config::default();
+        config.profile_mut("car").path = Some(String::from("/media/car"));
+
+        config_file.store(&config).unwrap();
+
+        let config = config_file.load().unwrap();
+        assert_eq!(Some(String::from("/media/car")), config.profile("car").path);
+        assert_eq!(None, config.profile("default").path);
+    }
+
+    #[test]
+    fn stores_each_rewrite_rule_as_given() {
+        let mut profile = Profile::default();
+
+        profile
+            .set(
+                "rewrites",
+                values(&["prefix:/music/iTunes=>Music", "regex:^/a;b/(.*)=>Music/$1"]),
+            )
+            .unwrap();
+
+        assert_eq!(
+            values(&["prefix:/music/iTunes=>Music", "regex:^/a;b/(.*)=>Music/$1"]),
+            profile.rewrites
+        );
+    }
+
+    #[test]
+    fn keeps_rewrite_rules_apart_in_the_configuration_file() {
+        let directory = TempDir::new();
+        let config_file = ConfigFile::new(
+            Some(
+                directory
+                    .path()
+                    .join("config.toml")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            false,
+        );
+        let mut config = Config::default();
+        let rewrites = values(&["prefix:/music/iTunes=>Music", "regex:^/a;b/(.*)=>Music/$1"]);
+        config
+            .profile_mut("car")
+            .set("rewrites", rewrites.clone())
+            .unwrap();
+
+        config_file.store(&config).unwrap();
+
+        let stored = std::fs::read_to_string(config_file.location().unwrap()).unwrap();
+        assert!(stored.contains("rewrites = ["));
+        assert_eq!(
+            rewrites,
+            config_file.load().unwrap().profile("car").rewrites
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rewrite_rules() {
+        let mut profile = Profile::default();
+
+        let result = profile.set("rewrites", values(&["prefix:/music=>Music", "regex:(=>x"]));
+
+        assert!(matches!(result, Err(ConfigError::InvalidValue(_, _))));
+        assert!(profile.rewrites.is_empty());
+    }
+
+    #[test]
+    fn clears_rewrite_rules_without_values() {
+        let mut profile = Profile {
+            rewrites: values(&["prefix:/music=>Music"]),
+            ..Profile::default()
+        };
+
+        profile.set("rewrites", vec![]).unwrap();
+
+        assert!(profile.rewrites.is_empty());
+    }
+
+    #[test]
+    fn rejects_several_values_for_other_keys() {
+        let mut profile = Profile::default();
+
+        let result = profile.set("server", values(&["http://a:32400", "http://b:32400"]));
+
+        assert!(matches!(result, Err(ConfigError::InvalidValue(_, _))));
+        assert_eq!(None, profile.server);
+    }
+
+    #[test]
+    fn does_not_store_profiles_in_dry_run() {
+        let directory = TempDir::new();
+        let path = directory.path().join("config.toml");
+        let config_file = ConfigFile::new(Some(path.to_string_lossy().to_string()), true);
+        let mut config = Config::default();
+        config.profile_mut("car").path = Some(String::from("/media/car"));
+
+        config_file.store(&config).unwrap();
+
+        assert!(!path.exists());
+        assert_eq!(None, config_file.load().unwrap().profile("car").path);
+    }
+}
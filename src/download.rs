@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+
+use log::info;
+use reqwest::StatusCode;
+
+use crate::m3u::Item;
+use crate::paths::PathOptions;
+use crate::plex_client::{PlexClient, PlexError};
+
+const PART_EXTENSION: &str = ".part";
+
+#[derive(Debug)]
+pub enum DownloadError {
+    MissingTrackKey,
+    SizeMismatch { expected: u64, actual: u64 },
+    Plex(PlexError),
+    Io(io::Error),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::MissingTrackKey => write!(f, "no track key in playlist"),
+            DownloadError::SizeMismatch { expected, actual } => {
+                write!(f, "expected {} bytes but downloaded {}", expected, actual)
+            }
+            DownloadError::Plex(error) => write!(f, "{}", error),
+            DownloadError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl DownloadError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            DownloadError::Plex(error) => error.status(),
+            _ => None,
+        }
+    }
+}
+
+impl From<PlexError> for DownloadError {
+    fn from(error: PlexError) -> Self {
+        DownloadError::Plex(error)
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(error: io::Error) -> Self {
+        DownloadError::Io(error)
+    }
+}
+
+pub struct DownloadResult {
+    pub item: Item,
+    pub outcome: Result<PathBuf, DownloadError>,
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    pub downloaded: usize,
+    pub failed: usize,
+    pub failed_statuses: BTreeMap<StatusCode, usize>,
+}
+
+impl DownloadSummary {
+    pub fn total(&self) -> usize {
+        self.downloaded + self.failed
+    }
+}
+
+impl fmt::Display for DownloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Downloaded {} / {} tracks ({} failed)",
+            self.downloaded,
+            self.total(),
+            self.failed
+        )?;
+        for (status, count) in self.failed_statuses.iter() {
+            write!(f, "\n\t{}: {}", status, count)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn download_part(
+    plex_client: &PlexClient,
+    track: &Item,
+    root_path: &Path,
+    path_options: &PathOptions,
+) -> Result<PathBuf, DownloadError> {
+    let key = track.track_key().ok_or(DownloadError::MissingTrackKey)?;
+    let full_path = track.local_path(root_path, path_options);
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part_path = part_path(&full_path);
+    let offset = match fs::metadata(&part_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+
+    let outcome = match fetch_part(plex_client, &key, &part_path, offset, track.size()) {
+        Err(DownloadError::SizeMismatch { .. }) if offset > 0 => {
+            info!("Discarding {:?} and downloading it again", part_path);
+            fs::remove_file(&part_path)?;
+            fetch_part(plex_client, &key, &part_path, 0, track.size())
+        }
+        outcome => outcome,
+    };
+    if let Err(DownloadError::SizeMismatch { .. }) = outcome {
+        fs::remove_file(&part_path)?;
+    }
+    outcome?;
+    fs::rename(&part_path, &full_path)?;
+    Ok(full_path)
+}
+
+fn fetch_part(
+    plex_client: &PlexClient,
+    key: &str,
+    part_path: &Path,
+    offset: u64,
+    expected_size: Option<u64>,
+) -> Result<(), DownloadError> {
+    let mut response = plex_client.get_part(key.to_string(), offset)?;
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let mut out = OpenOptions::new().append(true).open(part_path)?;
+            io::copy(&mut response, &mut out)?;
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {}
+        _ => {
+            let mut out = File::create(part_path)?;
+            io::copy(&mut response, &mut out)?;
+        }
+    }
+    if let Some(expected) = expected_size {
+        let actual = fs::metadata(part_path)?.len();
+        if actual != expected {
+            return Err(DownloadError::SizeMismatch { expected, actual });
+        }
+    }
+    Ok(())
+}
+
+pub fn remaining_size(track: &Item, root_path: &Path, path_options: &PathOptions) -> Option<u64> {
+    let full_path = track.local_path(root_path, path_options);
+    let offset = match fs::metadata(part_path(&full_path)) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    track.size().map(|size| size.saturating_sub(offset))
+}
+
+pub fn part_path(full_path: &Path) -> PathBuf {
+    let mut file_name = full_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(PART_EXTENSION);
+    full_path.with_file_name(file_name)
+}
+
+pub fn download_all<F>(
+    plex_client: &PlexClient,
+    tracks: Vec<Item>,
+    root_path: &Path,
+    path_options: &PathOptions,
+    jobs: usize,
+    mut on_result: F,
+) -> DownloadSummary
+where
+    F: FnMut(&DownloadResult),
+{
+    let queue = Mutex::new(VecDeque::from(tracks));
+    let (sender, receiver) = mpsc::channel::<DownloadResult>();
+    let mut summary = DownloadSummary::default();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            let sender = sender.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                loop {
+                    let next = queue.lock().unwrap().pop_front();
+                    let Some(item) = next else { break };
+                    let outcome = download_part(plex_client, &item, root_path, path_options);
+                    if sender.send(DownloadResult { item, outcome }).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for result in receiver {
+            match &result.outcome {
+                Ok(_) => summary.downloaded += 1,
+                Err(error) => {
+                    summary.failed += 1;
+                    if let Some(status) = error.status() {
+                        *summary.failed_statuses.entry(status).or_insert(0) += 1;
+                    }
+                }
+            }
+            on_result(&result);
+        }
+    });
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::m3u::TrackData;
+    use crate::test_support::{Response, TempDir, TestServer};
+
+    const KEY: &str = "/library/parts/1/file.mp3";
+
+    fn content() -> Vec<u8> {
+        (0..1000).map(|index| (index % 251) as u8).collect()
+    }
+
+    fn media_server() -> TestServer {
+        TestServer::start(|request| {
+            let content = content();
+            if request.path != KEY {
+                return Response::new(404, "text/html", "<html>Not Found</html>");
+            }
+            let start = request
+                .header("Range")
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok());
+            match start {
+                Some(start) if start >= content.len() => Response::new(416, "text/plain", ""),
+                Some(start) => Response::new(206, "audio/mpeg", &content[start..]),
+                None => Response::new(200, "audio/mpeg", content),
+            }
+        })
+    }
+
+    fn track(key: &str, size: u64) -> Item {
+        Item::new(
+            String::from("Artist/Song.mp3"),
+            vec![TrackData::Key(key.to_string()), TrackData::Size(size)],
+        )
+    }
+
+    fn download(
+        server: &TestServer,
+        root: &TempDir,
+        track: &Item,
+    ) -> Result<PathBuf, DownloadError> {
+        let plex_client = PlexClient::new(server.url.clone(), None);
+        download_part(&plex_client, track, root.path(), &PathOptions::default())
+    }
+
+    #[test]
+    fn downloads_a_part() {
+        let server = media_server();
+        let root = TempDir::new();
+
+        let full_path = download(&server, &root, &track(KEY, 1000)).unwrap();
+
+        assert_eq!(content(), fs::read(full_path).unwrap());
+        assert!(!root.path().join("Artist/Song.mp3.part").exists());
+    }
+
+    #[test]
+    fn resumes_from_a_part_file() {
+        let server = media_server();
+        let root = TempDir::new();
+        root.file("Artist/Song.mp3.part", &content()[..400]);
+
+        let full_path = download(&server, &root, &track(KEY, 1000)).unwrap();
+
+        assert_eq!(content(), fs::read(full_path).unwrap());
+        assert_eq!(Some("bytes=400-"), server.requests()[0].header("Range"));
+    }
+
+    #[test]
+    fn completes_a_part_file_when_the_range_is_not_satisfiable() {
+        let server = media_server();
+        let root = TempDir::new();
+        root.file("Artist/Song.mp3.part", &content());
+
+        let full_path = download(&server, &root, &track(KEY, 1000)).unwrap();
+
+        assert_eq!(content(), fs::read(full_path).unwrap());
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn restarts_a_part_file_of_the_wrong_size() {
+        let server = media_server();
+        let root = TempDir::new();
+        root.file("Artist/Song.mp3.part", &[0; 1200]);
+
+        let full_path = download(&server, &root, &track(KEY, 1000)).unwrap();
+
+        assert_eq!(content(), fs::read(full_path).unwrap());
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(None, requests[1].header("Range"));
+    }
+
+    #[test]
+    fn rejects_a_part_of_the_wrong_size() {
+        let server = media_server();
+        let root = TempDir::new();
+
+        let result = download(&server, &root, &track(KEY, 900));
+
+        assert!(matches!(
+            result,
+            Err(DownloadError::SizeMismatch {
+                expected: 900,
+                actual: 1000
+            })
+        ));
+        assert!(!root.path().join("Artist/Song.mp3").exists());
+        assert!(!root.path().join("Artist/Song.mp3.part").exists());
+    }
+
+    #[test]
+    fn rejects_error_statuses_and_non_media_bodies() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/library/parts/2/file.mp3" => Response::new(200, "text/html", "<html>error</html>"),
+            _ => Response::new(404, "text/html", "<html>Not Found</html>"),
+        });
+        let root = TempDir::new();
+
+        let missing = download(&server, &root, &track("/library/parts/1/file.mp3", 1000));
+        let html = download(&server, &root, &track("/library/parts/2/file.mp3", 1000));
+
+        assert_eq!(Some(StatusCode::NOT_FOUND), missing.unwrap_err().status());
+        assert!(matches!(
+            html,
+            Err(DownloadError::Plex(PlexError::UnexpectedContent(_)))
+        ));
+        assert!(!root.path().join("Artist/Song.mp3").exists());
+    }
+
+    #[test]
+    fn counts_downloads_and_failures_by_status() {
+        let server = media_server();
+        let root = TempDir::new();
+        let plex_client = PlexClient::new(server.url.clone(), None);
+        let tracks = vec![
+            Item::new(
+                String::from("One.mp3"),
+                vec![TrackData::Key(KEY.to_string())],
+            ),
+            Item::new(
+                String::from("Two.mp3"),
+                vec![TrackData::Key(KEY.to_string())],
+            ),
+            Item::new(
+                String::from("Missing.mp3"),
+                vec![TrackData::Key(String::from("/library/parts/2/file.mp3"))],
+            ),
+            Item::new(String::from("Unkeyed.mp3"), vec![]),
+        ];
+        let mut results = 0;
+
+        let summary = download_all(
+            &plex_client,
+            tracks,
+            root.path(),
+            &PathOptions::default(),
+            2,
+            |_| results += 1,
+        );
+
+        assert_eq!(4, results);
+        assert_eq!(2, summary.downloaded);
+        assert_eq!(2, summary.failed);
+        assert_eq!(
+            BTreeMap::from([(StatusCode::NOT_FOUND, 1)]),
+            summary.failed_statuses
+        );
+        assert!(root.path().join("One.mp3").exists());
+        assert!(root.path().join("Two.mp3").exists());
+    }
+
+    #[test]
+    fn counts_the_remaining_size_past_a_part_file() {
+        let root = TempDir::new();
+        let track = track(KEY, 1000);
+        let full_path = track.local_path(root.path(), &PathOptions::default());
+
+        assert_eq!(
+            Some(1000),
+            remaining_size(&track, root.path(), &PathOptions::default())
+        );
+
+        fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+        fs::write(part_path(&full_path), &content()[..400]).unwrap();
+
+        assert_eq!(
+            Some(600),
+            remaining_size(&track, root.path(), &PathOptions::default())
+        );
+    }
+}
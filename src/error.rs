@@ -0,0 +1,41 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::capacity::NotEnoughSpace;
+use crate::plex_client::PlexError;
+
+#[derive(Debug)]
+pub enum CommandError {
+    Plex(PlexError),
+    Read(String, io::Error),
+    Write(String, io::Error),
+    Scan(PathBuf, io::Error),
+    InvalidPath(PathBuf),
+    NotEnoughSpace(NotEnoughSpace),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Plex(error) => write!(f, "{}", error),
+            CommandError::Read(file, error) => write!(f, "could not read {}: {}", file, error),
+            CommandError::Write(file, error) => write!(f, "could not write {}: {}", file, error),
+            CommandError::Scan(path, error) => write!(f, "could not scan {:?}: {}", path, error),
+            CommandError::InvalidPath(path) => write!(f, "{:?} is not valid UTF-8", path),
+            CommandError::NotEnoughSpace(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<PlexError> for CommandError {
+    fn from(error: PlexError) -> Self {
+        CommandError::Plex(error)
+    }
+}
+
+impl From<NotEnoughSpace> for CommandError {
+    fn from(error: NotEnoughSpace) -> Self {
+        CommandError::NotEnoughSpace(error)
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+const REDACTED: &str = "[REDACTED]";
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+struct RedactingLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = redact(record.args().to_string());
+        self.inner.log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("{}", message))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+pub fn init(level: LevelFilter) {
+    let mut builder = colog::default_builder();
+    builder.filter(None, level);
+    let logger = RedactingLogger {
+        inner: Box::new(builder.build()),
+    };
+    log::set_boxed_logger(Box::new(logger)).expect("Logger already initialized");
+    log::set_max_level(level);
+}
+
+pub fn add_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|existing| existing == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+pub fn redact(message: String) -> String {
+    SECRETS
+        .read()
+        .unwrap()
+        .iter()
+        .fold(message, |message, secret| message.replace(secret, REDACTED))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use log::Level;
+
+    use super::*;
+
+    struct CapturingLogger(Arc<Mutex<Vec<String>>>);
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn redacts_secrets_from_messages() {
+        add_secret("REDACTSECRET");
+        add_secret("");
+
+        assert_eq!(
+            "GET http://plex/library?X-Plex-Token=[REDACTED]&a=[REDACTED]",
+            redact(String::from(
+                "GET http://plex/library?X-Plex-Token=REDACTSECRET&a=REDACTSECRET"
+            ))
+        );
+        assert_eq!("nothing to hide", redact(String::from("nothing to hide")));
+    }
+
+    #[test]
+    fn redacts_secrets_from_log_lines() {
+        add_secret("LOGSECRET");
+        let lines = Arc::new(Mutex::new(vec![]));
+        let logger = RedactingLogger {
+            inner: Box::new(CapturingLogger(lines.clone())),
+        };
+
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("GET {}", "http://plex/?token=LOGSECRET"))
+                .build(),
+        );
+
+        assert_eq!(
+            vec![String::from("GET http://plex/?token=[REDACTED]")],
+            *lines.lock().unwrap()
+        );
+    }
+}
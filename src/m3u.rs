@@ -1,18 +1,44 @@
-use std::io::{self, prelude::*};
+use std::fmt;
+use std::io::prelude::*;
 use std::path::PathBuf;
-use std::{fs::File, path::Path};
+use std::{fs, fs::File, path::Path};
+
+use encoding_rs::WINDOWS_1252;
+
+use crate::paths::{self, PathOptions};
+use crate::rewrite::RewriteRule;
 
 const HEADER_LINE: &str = "#EXTM3U";
+const BYTE_ORDER_MARK: char = '\u{feff}';
 
 #[derive(Debug)]
 pub struct M3U {
     pub tracks: Vec<Item>,
     pub metadata: Vec<Metadata>,
+    pub trailing: Vec<TrackData>,
+    pub header: Option<String>,
 }
 
 impl M3U {
     pub fn new(tracks: Vec<Item>, metadata: Vec<Metadata>) -> M3U {
-        M3U { tracks, metadata }
+        M3U {
+            tracks,
+            metadata,
+            trailing: vec![],
+            header: Some(HEADER_LINE.to_string()),
+        }
+    }
+
+    fn has_playlist(&self) -> bool {
+        self.metadata.iter().any(|meta| meta.is_playlist())
+            || self
+                .tracks
+                .iter()
+                .flat_map(|track| track.metadata.iter())
+                .chain(self.trailing.iter())
+                .any(|track_data| {
+                    matches!(track_data, TrackData::Directive(directive) if directive.starts_with("PLAYLIST:"))
+                })
     }
 }
 
@@ -22,9 +48,32 @@ pub struct Item {
     pub metadata: Vec<TrackData>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TrackData {
     Key(String),
+    Size(u64),
+    Duration(u64),
+    UpdatedAt(u64),
+    Info(ExtInf),
+    Album(String),
+    Artist(String),
+    Group(String),
+    Directive(String),
+    Blank,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtInf {
+    pub duration: i64,
+    pub artist: Option<String>,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackState {
+    Present,
+    Missing,
+    Stale { expected: u64, actual: u64 },
 }
 
 #[derive(Debug)]
@@ -33,6 +82,11 @@ pub enum Metadata {
     Title(String),
     RewriteFrom(String),
     RewriteTo(String),
+    Rewrite(RewriteRule),
+    Template(String),
+    Playlist(String),
+    Directive(String),
+    Blank,
     TrackData(TrackData),
 }
 
@@ -49,28 +103,92 @@ impl M3UAttribute {
     pub fn new(key: String, value: String) -> M3UAttribute {
         M3UAttribute { key, value }
     }
-    pub fn to_string(&self) -> String {
-        format!("PLEXM3U_{}:{}", self.key, self.value)
+}
+
+impl fmt::Display for M3UAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PLEXM3U_{}:{}", self.key, self.value)
     }
 }
 
-impl TrackData {
-    fn format(&self) -> M3UAttribute {
-        match self {
-            TrackData::Key(key) => M3UAttribute::new("TRACK_KEY".to_string(), key.clone()),
+impl ExtInf {
+    pub fn new(duration_ms: Option<u64>, artist: Option<String>, title: String) -> ExtInf {
+        ExtInf {
+            duration: duration_ms.map_or(-1, |duration| (duration as i64 + 500) / 1000),
+            artist,
+            title,
         }
     }
 
-    pub fn is_key(&self) -> bool {
-        match self {
-            TrackData::Key(_) => true,
+    fn parse(value: &str) -> Option<ExtInf> {
+        let (duration, name) = value.split_once(',')?;
+        let duration = duration
+            .parse::<i64>()
+            .ok()
+            .filter(|parsed| parsed.to_string() == duration)?;
+        let (artist, title) = match name.split_once(" - ") {
+            Some((artist, title)) => (Some(artist.to_string()), title.to_string()),
+            None => (None, name.to_string()),
+        };
+        Some(ExtInf {
+            duration,
+            artist,
+            title,
+        })
+    }
+}
+
+impl fmt::Display for ExtInf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.artist {
+            Some(artist) => write!(f, "EXTINF:{},{} - {}", self.duration, artist, self.title),
+            None => write!(f, "EXTINF:{},{}", self.duration, self.title),
         }
     }
 }
 
-impl Metadata {
-    fn format(&self) -> M3UAttribute {
+impl fmt::Display for TrackData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            TrackData::Key(key) => write!(
+                f,
+                "{}",
+                M3UAttribute::new("TRACK_KEY".to_string(), key.clone())
+            ),
+            TrackData::Size(size) => write!(
+                f,
+                "{}",
+                M3UAttribute::new("TRACK_SIZE".to_string(), size.to_string())
+            ),
+            TrackData::Duration(duration) => write!(
+                f,
+                "{}",
+                M3UAttribute::new("TRACK_DURATION".to_string(), duration.to_string())
+            ),
+            TrackData::UpdatedAt(updated_at) => write!(
+                f,
+                "{}",
+                M3UAttribute::new("TRACK_UPDATED_AT".to_string(), updated_at.to_string())
+            ),
+            TrackData::Info(ext_inf) => write!(f, "{}", ext_inf),
+            TrackData::Album(album) => write!(f, "EXTALB:{}", album),
+            TrackData::Artist(artist) => write!(f, "EXTART:{}", artist),
+            TrackData::Group(group) => write!(f, "EXTGRP:{}", group),
+            TrackData::Directive(directive) => write!(f, "{}", directive),
+            TrackData::Blank => Ok(()),
+        }
+    }
+}
+
+impl TrackData {
+    pub fn is_key(&self) -> bool {
+        matches!(self, TrackData::Key(_))
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let attribute = match self {
             Metadata::RatingKey(rating_key) => {
                 M3UAttribute::new("RATING_KEY".to_string(), rating_key.to_string())
             }
@@ -81,19 +199,40 @@ impl Metadata {
             Metadata::RewriteTo(rewrite_to) => {
                 M3UAttribute::new("REWRITE_TO".to_string(), rewrite_to.clone())
             }
-            Metadata::TrackData(track_data) => track_data.format(),
-        }
+            Metadata::Rewrite(rule) => M3UAttribute::new("REWRITE".to_string(), rule.to_string()),
+            Metadata::Template(template) => {
+                M3UAttribute::new("TEMPLATE".to_string(), template.clone())
+            }
+            Metadata::Playlist(playlist) => return write!(f, "PLAYLIST:{}", playlist),
+            Metadata::Directive(directive) => return write!(f, "{}", directive),
+            Metadata::Blank => return Ok(()),
+            Metadata::TrackData(track_data) => return write!(f, "{}", track_data),
+        };
+        write!(f, "{}", attribute)
     }
+}
 
+impl Metadata {
     pub fn is_title(&self) -> bool {
-        match self {
-            Self::Title(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::Title(_))
+    }
+
+    pub fn is_playlist(&self) -> bool {
+        matches!(self, Self::Playlist(_))
     }
 
     pub fn parse(input: String) -> Option<Metadata> {
-        if input.starts_with("#PLEXM3U_") {
+        if let Some(value) = input.strip_prefix("#EXTINF:") {
+            ExtInf::parse(value).map(|ext_inf| Metadata::TrackData(TrackData::Info(ext_inf)))
+        } else if let Some(album) = input.strip_prefix("#EXTALB:") {
+            Some(Metadata::TrackData(TrackData::Album(album.to_string())))
+        } else if let Some(artist) = input.strip_prefix("#EXTART:") {
+            Some(Metadata::TrackData(TrackData::Artist(artist.to_string())))
+        } else if let Some(group) = input.strip_prefix("#EXTGRP:") {
+            Some(Metadata::TrackData(TrackData::Group(group.to_string())))
+        } else if let Some(playlist) = input.strip_prefix("#PLAYLIST:") {
+            Some(Metadata::Playlist(playlist.to_string()))
+        } else if input.starts_with("#PLEXM3U_") {
             let cleaned = input.replace("#PLEXM3U_", "");
             let mut splitted = cleaned.splitn(2, ":");
             match (splitted.next(), splitted.next()) {
@@ -111,9 +250,27 @@ impl Metadata {
                 (Some("REWRITE_TO"), Some(rewrite_to)) => {
                     Some(Metadata::RewriteTo(rewrite_to.to_string()))
                 }
+                (Some("REWRITE"), Some(rule)) => {
+                    rule.parse::<RewriteRule>().ok().map(Metadata::Rewrite)
+                }
+                (Some("TEMPLATE"), Some(template)) => {
+                    Some(Metadata::Template(template.to_string()))
+                }
                 (Some("TRACK_KEY"), Some(track_key)) => {
                     Some(Metadata::TrackData(TrackData::Key(track_key.to_string())))
                 }
+                (Some("TRACK_SIZE"), Some(size)) => size
+                    .parse::<u64>()
+                    .ok()
+                    .map(|size| Metadata::TrackData(TrackData::Size(size))),
+                (Some("TRACK_DURATION"), Some(duration)) => duration
+                    .parse::<u64>()
+                    .ok()
+                    .map(|duration| Metadata::TrackData(TrackData::Duration(duration))),
+                (Some("TRACK_UPDATED_AT"), Some(updated_at)) => updated_at
+                    .parse::<u64>()
+                    .ok()
+                    .map(|updated_at| Metadata::TrackData(TrackData::UpdatedAt(updated_at))),
                 _ => None,
             }
         } else {
@@ -127,13 +284,25 @@ impl Item {
         Item { path, metadata }
     }
 
-    pub fn exists_at(&self, root_path: &Path) -> bool {
-        let full_path = self.full_path(root_path);
-        full_path.exists()
+    pub fn state_at(&self, root_path: &Path, path_options: &PathOptions) -> TrackState {
+        let metadata = fs::metadata(self.local_path(root_path, path_options));
+        match (metadata, self.size()) {
+            (Err(_), _) => TrackState::Missing,
+            (Ok(metadata), Some(expected)) if metadata.len() != expected => TrackState::Stale {
+                expected,
+                actual: metadata.len(),
+            },
+            (Ok(_), _) => TrackState::Present,
+        }
+    }
+
+    pub fn local_path(&self, root_path: &Path, path_options: &PathOptions) -> PathBuf {
+        let full_path = root_path.join(paths::to_local(&path_options.apply(&self.path)));
+        paths::locate(&full_path).unwrap_or(full_path)
     }
 
     pub fn full_path(&self, root_path: &Path) -> PathBuf {
-        root_path.join(self.path.clone())
+        root_path.join(paths::to_local(&self.path))
     }
 
     pub fn track_key(&self) -> Option<String> {
@@ -142,59 +311,285 @@ impl Item {
             _ => None,
         }
     }
+
+    pub fn updated_at(&self) -> Option<u64> {
+        self.metadata
+            .iter()
+            .find_map(|track_data| match track_data {
+                TrackData::UpdatedAt(updated_at) => Some(*updated_at),
+                _ => None,
+            })
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.metadata
+            .iter()
+            .find_map(|track_data| match track_data {
+                TrackData::Size(size) => Some(*size),
+                _ => None,
+            })
+    }
 }
 
-pub fn write<P: AsRef<Path>>(filename: P, m3u: M3U) -> std::io::Result<()> {
+pub fn write<P: AsRef<Path>>(filename: P, m3u: &M3U) -> std::io::Result<()> {
     let mut file = File::create(filename)?;
-    writeln!(file, "{}", HEADER_LINE)?;
-    for meta in m3u.metadata.iter().clone() {
-        writeln!(file, "#{}", meta.format().to_string())?;
+    write_to(&mut file, m3u)
+}
+
+pub fn write_to<W: Write>(out: &mut W, m3u: &M3U) -> std::io::Result<()> {
+    if let Some(header) = &m3u.header {
+        writeln!(out, "{}", header)?;
+    }
+    for meta in m3u.metadata.iter() {
+        match meta {
+            Metadata::Blank => writeln!(out)?,
+            meta => writeln!(out, "#{}", meta)?,
+        }
+    }
+    if !m3u.has_playlist()
+        && let Some(Metadata::Title(title)) = m3u.metadata.iter().find(|meta| meta.is_title())
+    {
+        writeln!(out, "#PLAYLIST:{}", title)?;
     }
-    if let Some(Metadata::Title(title)) = m3u.metadata.iter().find(|meta| meta.is_title()) {
-        writeln!(file, "#PLAYLIST:{}", title)?;
+    for line in m3u.tracks.iter() {
+        write_track_data(out, &line.metadata)?;
+        writeln!(out, "{}", line.path)?;
     }
-    for line in m3u.tracks {
-        for meta in line.metadata {
-            writeln!(file, "#{}", meta.format().to_string())?;
+    write_track_data(out, &m3u.trailing)
+}
+
+fn write_track_data<W: Write>(out: &mut W, track_data: &[TrackData]) -> std::io::Result<()> {
+    for meta in track_data.iter() {
+        match meta {
+            TrackData::Blank => writeln!(out)?,
+            meta => writeln!(out, "#{}", meta)?,
         }
-        writeln!(file, "{}", line.path)?;
     }
     Ok(())
 }
 
+fn decode(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(error) => WINDOWS_1252
+            .decode_without_bom_handling(error.as_bytes())
+            .0
+            .into_owned(),
+    }
+}
+
+fn is_header(line: &str) -> bool {
+    line.strip_prefix(HEADER_LINE)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
 pub fn read<P: AsRef<Path>>(filename: P) -> std::io::Result<M3U> {
-    let file = File::open(filename)?;
-    let reader = io::BufReader::new(file);
-    let mut lines = reader.lines();
+    let content = decode(fs::read(filename)?);
+    let content = content.trim_start_matches(BYTE_ORDER_MARK);
     let mut output_lines: Vec<Item> = Vec::new();
     let mut accumulated_track_meta: Vec<TrackData> = Vec::new();
     let mut output_metadata: Vec<Metadata> = Vec::new();
+    let mut header = None;
 
-    if let Some(Ok(first_line)) = lines.next()
-        && first_line == HEADER_LINE
-    {
-        for line_result in lines {
-            let line_content = line_result?;
-            match Metadata::parse(line_content.clone()) {
-                Some(Metadata::TrackData(track_data)) => {
-                    accumulated_track_meta.push(track_data);
-                }
-                Some(metadata) => output_metadata.push(metadata),
-                None => {
-                    if !line_content.starts_with("#") {
-                        let item = Item::new(line_content, accumulated_track_meta);
-                        output_lines.push(item);
-                        accumulated_track_meta = Vec::new();
+    for line_content in content.lines() {
+        let line_content = line_content.trim_end_matches('\r');
+        if is_header(line_content) && header.is_none() {
+            header = Some(line_content.to_string());
+            continue;
+        }
+        let in_header = output_lines.is_empty() && accumulated_track_meta.is_empty();
+        if line_content.trim().is_empty() {
+            if in_header {
+                output_metadata.push(Metadata::Blank);
+            } else {
+                accumulated_track_meta.push(TrackData::Blank);
+            }
+            continue;
+        }
+        match Metadata::parse(line_content.to_string()) {
+            Some(Metadata::TrackData(track_data)) => {
+                accumulated_track_meta.push(track_data);
+            }
+            Some(metadata) if in_header => output_metadata.push(metadata),
+            Some(_) => accumulated_track_meta.push(TrackData::Directive(
+                line_content.trim_start_matches('#').to_string(),
+            )),
+            None => {
+                if let Some(directive) = line_content.strip_prefix("#") {
+                    if in_header {
+                        output_metadata.push(Metadata::Directive(directive.to_string()));
+                    } else {
+                        accumulated_track_meta.push(TrackData::Directive(directive.to_string()));
                     }
+                } else {
+                    let item = Item::new(line_content.to_string(), accumulated_track_meta);
+                    output_lines.push(item);
+                    accumulated_track_meta = Vec::new();
                 }
             }
         }
+    }
 
-        Ok(M3U {
-            tracks: output_lines,
-            metadata: output_metadata,
-        })
-    } else {
-        panic!("M3u playlists must start with #EXTM3U")
+    Ok(M3U {
+        tracks: output_lines,
+        metadata: output_metadata,
+        trailing: accumulated_track_meta,
+        header,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/m3u")
+            .join(name)
+    }
+
+    fn round_trip(name: &str) -> String {
+        let output = env::temp_dir().join(format!("plexm3u-{}-{}", process::id(), name));
+        write(&output, &read(fixture(name)).unwrap()).unwrap();
+        let content = fs::read_to_string(&output).unwrap();
+        fs::remove_file(output).unwrap();
+        content
+    }
+
+    fn assert_lossless(name: &str) {
+        let original = fs::read_to_string(fixture(name)).unwrap();
+        assert_eq!(round_trip(name), original);
+    }
+
+    #[test]
+    fn round_trips_plexm3u_playlists() {
+        assert_lossless("plexm3u.m3u");
+    }
+
+    #[test]
+    fn round_trips_foreign_playlists() {
+        assert_lossless("foreign.m3u8");
+    }
+
+    #[test]
+    fn round_trips_playlists_without_header() {
+        assert_lossless("plain.m3u");
+    }
+
+    #[test]
+    fn round_trips_header_attributes() {
+        assert_lossless("header-attributes.m3u");
+        let m3u = read(fixture("header-attributes.m3u")).unwrap();
+        assert!(
+            !m3u.metadata
+                .iter()
+                .any(|meta| matches!(meta, Metadata::Directive(_)))
+        );
+    }
+
+    #[test]
+    fn keeps_directives_and_blank_lines_in_place() {
+        let original = fs::read(fixture("interleaved.m3u")).unwrap();
+        let mut output = vec![];
+        write_to(&mut output, &read(fixture("interleaved.m3u")).unwrap()).unwrap();
+        assert_eq!(output, original);
+
+        let m3u = read(fixture("interleaved.m3u")).unwrap();
+        assert!(!m3u.metadata.iter().any(|meta| meta.is_playlist()));
+        assert_eq!(
+            m3u.tracks[1].metadata[..2],
+            [
+                TrackData::Blank,
+                TrackData::Directive("PLAYLIST:Road Trip".to_string())
+            ]
+        );
+        assert_eq!(
+            m3u.trailing,
+            vec![
+                TrackData::Directive("PLEXM3U_TEMPLATE:{artist}/{album}/{title}".to_string()),
+                TrackData::Blank,
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_windows_1252_playlists() {
+        let expected = fs::read_to_string(fixture("windows-1252.expected.m3u")).unwrap();
+        assert_eq!(round_trip("windows-1252.m3u"), expected);
+    }
+
+    #[test]
+    fn normalizes_byte_order_mark_and_crlf() {
+        let expected = fs::read_to_string(fixture("windows.expected.m3u")).unwrap();
+        assert_eq!(round_trip("windows.m3u"), expected);
+    }
+
+    #[test]
+    fn reads_plexm3u_track_data() {
+        let m3u = read(fixture("plexm3u.m3u")).unwrap();
+        assert_eq!(m3u.tracks.len(), 2);
+        let track = &m3u.tracks[0];
+        assert_eq!(
+            track.track_key(),
+            Some("/library/parts/1/1586553226/file.m4a".to_string())
+        );
+        assert_eq!(track.size(), Some(9421322));
+        assert!(track.metadata.contains(&TrackData::Info(ExtInf {
+            duration: 305,
+            artist: Some("Rush".to_string()),
+            title: "Manhattan Project".to_string(),
+        })));
+    }
+
+    #[test]
+    fn keeps_unknown_directives_in_place() {
+        let m3u = read(fixture("foreign.m3u8")).unwrap();
+        assert!(m3u.metadata.iter().any(
+            |meta| matches!(meta, Metadata::Directive(directive) if directive == "EXTENC:UTF-8")
+        ));
+        assert!(
+            m3u.tracks[1]
+                .metadata
+                .contains(&TrackData::Directive("EXTVLCOPT:start-time=12".to_string()))
+        );
+        assert_eq!(
+            m3u.trailing,
+            vec![
+                TrackData::Directive(" end of playlist".to_string()),
+                TrackData::Directive("EXT-X-ENDLIST".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_ext_inf_in_seconds() {
+        let with_artist = ExtInf::new(
+            Some(305_499),
+            Some("Rush".to_string()),
+            "Manhattan Project".to_string(),
+        );
+        let without_duration = ExtInf::new(None, None, "Intro".to_string());
+
+        assert_eq!(
+            "EXTINF:305,Rush - Manhattan Project",
+            with_artist.to_string()
+        );
+        assert_eq!("EXTINF:-1,Intro", without_duration.to_string());
+    }
+
+    #[test]
+    fn parses_ext_inf_lines() {
+        let metadata = Metadata::parse("#EXTINF:305,Rush - Manhattan Project".to_string());
+
+        assert!(matches!(
+            metadata,
+            Some(Metadata::TrackData(TrackData::Info(ext_inf))) if ext_inf == ExtInf {
+                duration: 305,
+                artist: Some("Rush".to_string()),
+                title: "Manhattan Project".to_string(),
+            }
+        ));
     }
 }
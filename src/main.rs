@@ -1,21 +1,60 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::io;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
-use clap::{Args, Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use log::LevelFilter;
-use log::error;
-use plex_client::PlexClient;
+use log::{error, warn};
+use plex_client::{PlexClient, PlexError};
 
-use crate::m3u::Item;
-use crate::m3u::M3U;
+use crate::capacity::{Budget, NotEnoughSpace};
+use crate::config::{Config, ConfigError, ConfigFile, Profile};
+use crate::error::CommandError;
+use crate::m3u::TrackState;
 use crate::m3u::WithMetadata;
-use crate::plex_client::playlist::PlaylistFilter;
+use crate::m3u::{Item, M3U};
+use crate::manifest::{Manifest, PlaylistEntry};
+use crate::paths::{Normalization, PathOptions, PathStyle, TargetFs};
+use crate::plex_client::gdm::{self, GDM_ADDRESS, GDM_PORT};
+use crate::plex_client::playlist::{Playlist, PlaylistFilter};
+use crate::plex_client::plex_tv::{PLEX_TV_URL, PlexTvClient};
+use crate::plex_client::server::{self, Server};
 use crate::plex_client::track::WithMedia;
+use crate::prune::References;
+use crate::relocator::{Relocation, Relocator};
+use crate::rewrite::RewriteRule;
+use crate::template::PathTemplate;
 
+mod capacity;
+mod config;
+mod download;
+mod error;
+mod logger;
 mod m3u;
+mod manifest;
+mod paths;
 mod plex_client;
+mod prune;
+mod relocator;
+mod resolver;
+mod rewrite;
+mod template;
+#[cfg(test)]
+mod test_support;
+
+const DEFAULT_JOBS: usize = 4;
+const DEFAULT_PAGE_SIZE: u32 = 500;
+const DEFAULT_LOGIN_TIMEOUT: u64 = 300;
+const DEFAULT_GDM_TIMEOUT: u64 = 2000;
+const DEFAULT_CHECK_TIMEOUT: u64 = 3000;
+const DEFAULT_MIN_CONFIDENCE: u8 = 80;
+const LOGIN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const NO_PLAYLIST_EXIT_CODE: u8 = 3;
 
 #[derive(Parser)]
 struct Cli {
@@ -23,12 +62,18 @@ struct Cli {
     command: Option<Command>,
     #[arg(short, long)]
     verbose: bool,
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    #[arg(long, global = true)]
+    config: Option<String>,
+    #[arg(long, global = true)]
+    dry_run: bool,
 }
 
 #[derive(Debug, Args)]
 struct DumpPlaylistArguments {
     #[arg(short, long)]
-    server: String,
+    server: Option<String>,
     #[arg(short, long)]
     token: Option<String>,
     rating_key: String,
@@ -36,25 +81,43 @@ struct DumpPlaylistArguments {
     rewrite_from: Option<String>,
     #[arg(long)]
     rewrite_to: Option<String>,
+    #[arg(long = "rewrite")]
+    rewrites: Vec<RewriteRule>,
+    #[arg(long)]
+    template: Option<PathTemplate>,
+    #[arg(long, value_enum)]
+    path_style: Option<PathStyle>,
+    #[arg(long)]
+    track_root: Option<String>,
+    #[arg(long)]
+    drive: Option<String>,
     #[arg(long, short)]
     file: Option<String>,
     #[arg(long)]
     stdout: bool,
+    #[arg(long, default_value_t = DEFAULT_PAGE_SIZE)]
+    page_size: u32,
+    #[arg(long, value_enum)]
+    normalization: Option<Normalization>,
+    #[arg(long, value_enum)]
+    target_fs: Option<TargetFs>,
 }
 
 #[derive(Debug, Args)]
 struct GetPlaylistArguments {
     #[arg(short, long)]
-    server: String,
+    server: Option<String>,
     #[arg(short, long)]
     token: Option<String>,
     rating_key: String,
+    #[arg(long, default_value_t = DEFAULT_PAGE_SIZE)]
+    page_size: u32,
 }
 
 #[derive(Debug, Args)]
 struct PlaylistsFilterArguments {
     #[arg(short, long)]
-    server: String,
+    server: Option<String>,
     #[arg(short, long)]
     token: Option<String>,
     #[arg(long)]
@@ -73,23 +136,126 @@ struct VerifyM3uArguments {
     token: Option<String>,
     #[arg(short, long)]
     server: Option<String>,
+    #[arg(long, short, default_value_t = DEFAULT_JOBS)]
+    jobs: usize,
+    #[arg(long)]
+    relocate: bool,
+    #[arg(long, value_enum)]
+    normalization: Option<Normalization>,
+    #[arg(long, value_enum)]
+    target_fs: Option<TargetFs>,
+    #[arg(long)]
+    confirm: bool,
+    #[arg(long, default_value_t = DEFAULT_MIN_CONFIDENCE, value_parser = clap::value_parser!(u8).range(0..=100))]
+    min_confidence: u8,
+    #[arg(long, value_parser = capacity::parse_size)]
+    budget: Option<u64>,
 }
 
 #[derive(Debug, Args)]
 struct SyncArguments {
     rating_keys: Vec<String>,
+    #[arg(long)]
+    all: bool,
+    #[arg(long = "type")]
+    playlist_type: Option<String>,
+    #[arg(long, overrides_with = "no_smart")]
+    smart: bool,
+    #[arg(long, overrides_with = "smart")]
+    no_smart: bool,
+    #[arg(long)]
+    title_glob: Option<String>,
+    #[arg(long)]
+    exclude: Vec<String>,
     #[arg(long, short)]
-    path: String,
+    path: Option<String>,
     #[arg(short, long)]
-    server: String,
+    server: Option<String>,
     #[arg(short, long)]
     token: Option<String>,
     #[arg(long)]
     rewrite_from: Option<String>,
     #[arg(long)]
     rewrite_to: Option<String>,
+    #[arg(long = "rewrite")]
+    rewrites: Vec<RewriteRule>,
+    #[arg(long)]
+    template: Option<PathTemplate>,
+    #[arg(long, value_enum)]
+    path_style: Option<PathStyle>,
+    #[arg(long)]
+    drive: Option<String>,
     #[arg(long)]
     fix: bool,
+    #[arg(long)]
+    full: bool,
+    #[arg(long)]
+    prune: bool,
+    #[arg(long, value_parser = capacity::parse_size)]
+    budget: Option<u64>,
+    #[arg(long, short, default_value_t = DEFAULT_JOBS)]
+    jobs: usize,
+    #[arg(long, default_value_t = DEFAULT_PAGE_SIZE)]
+    page_size: u32,
+    #[arg(long, value_enum)]
+    normalization: Option<Normalization>,
+    #[arg(long, value_enum)]
+    target_fs: Option<TargetFs>,
+}
+
+#[derive(Debug, Args)]
+struct ConfigArguments {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Debug, Args)]
+struct ConfigSetArguments {
+    key: String,
+    values: Vec<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    Show,
+    Set(ConfigSetArguments),
+}
+
+#[derive(Debug, Args)]
+struct LoginArguments {
+    #[arg(long, default_value = PLEX_TV_URL)]
+    plex_tv_url: String,
+    #[arg(long, default_value_t = DEFAULT_LOGIN_TIMEOUT)]
+    timeout: u64,
+}
+
+#[derive(Debug, Args)]
+struct WhyArguments {
+    file: String,
+    #[arg(long, short)]
+    path: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct ServersArguments {
+    #[arg(short, long)]
+    token: Option<String>,
+    #[arg(long, default_value = PLEX_TV_URL)]
+    plex_tv_url: String,
+    #[arg(long, default_value = GDM_ADDRESS)]
+    gdm_address: String,
+    #[arg(long, default_value_t = GDM_PORT)]
+    gdm_port: u16,
+    #[arg(long, default_value_t = DEFAULT_GDM_TIMEOUT)]
+    gdm_timeout: u64,
+    #[arg(long)]
+    no_gdm: bool,
+    #[arg(long, default_value_t = DEFAULT_CHECK_TIMEOUT)]
+    check_timeout: u64,
+    #[arg(long)]
+    no_check: bool,
+    #[arg(long)]
+    save: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -99,109 +265,957 @@ enum Command {
     DumpPlaylist(DumpPlaylistArguments),
     VerifyM3u(VerifyM3uArguments),
     Sync(SyncArguments),
+    Config(ConfigArguments),
+    Login(LoginArguments),
+    Servers(ServersArguments),
+    Why(WhyArguments),
+}
+
+impl DumpPlaylistArguments {
+    fn with_profile(self, profile: &Profile) -> Self {
+        DumpPlaylistArguments {
+            server: self.server.or(profile.server.clone()),
+            token: self.token.or(profile.server_token()),
+            rewrite_from: self.rewrite_from.or(profile.rewrite_from.clone()),
+            rewrite_to: self.rewrite_to.or(profile.rewrite_to.clone()),
+            rewrites: rewrites_or_profile(self.rewrites, profile),
+            template: self.template.or_else(|| profile_template(profile)),
+            file: self.file.or(profile.path.clone().filter(|_| !self.stdout)),
+            ..self
+        }
+    }
+
+    fn path_options(&self) -> PathOptions {
+        PathOptions {
+            normalization: self.normalization,
+            target_fs: self.target_fs,
+        }
+    }
+
+    fn rewrite_rules(&self) -> Vec<RewriteRule> {
+        let mut rewrite_rules = self.rewrites.clone();
+        if let Some(from) = &self.rewrite_from {
+            rewrite_rules.push(RewriteRule::Literal {
+                from: from.clone(),
+                to: self.rewrite_to.clone().unwrap_or_default(),
+            });
+        }
+        rewrite_rules
+    }
+}
+
+impl GetPlaylistArguments {
+    fn with_profile(self, profile: &Profile) -> Self {
+        GetPlaylistArguments {
+            server: self.server.or(profile.server.clone()),
+            token: self.token.or(profile.server_token()),
+            ..self
+        }
+    }
+}
+
+impl PlaylistsFilterArguments {
+    fn with_profile(self, profile: &Profile) -> Self {
+        PlaylistsFilterArguments {
+            server: self.server.or(profile.server.clone()),
+            token: self.token.or(profile.server_token()),
+            ..self
+        }
+    }
+}
+
+impl VerifyM3uArguments {
+    fn root_path(&self) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&self.file).parent().unwrap().to_path_buf(),
+        }
+    }
+
+    fn path_options(&self) -> PathOptions {
+        PathOptions {
+            normalization: self.normalization,
+            target_fs: self.target_fs,
+        }
+    }
+
+    fn with_profile(self, profile: &Profile) -> Self {
+        VerifyM3uArguments {
+            server: self.server.or(profile.server.clone()),
+            token: self.token.or(profile.server_token()),
+            budget: self.budget.or_else(|| profile_budget(profile)),
+            ..self
+        }
+    }
+}
+
+impl WhyArguments {
+    fn with_profile(self, profile: &Profile) -> Self {
+        WhyArguments {
+            path: self.path.or(profile.path.clone()),
+            ..self
+        }
+    }
+}
+
+impl ServersArguments {
+    fn with_profile(self, profile: &Profile) -> Self {
+        ServersArguments {
+            token: self.token.or(profile.token.clone()),
+            ..self
+        }
+    }
 }
 
-fn main() {
+impl SyncArguments {
+    fn verify_m3u_arguments(&self, file: String) -> VerifyM3uArguments {
+        VerifyM3uArguments {
+            file,
+            path: None,
+            fix: self.fix,
+            server: self.server.clone(),
+            token: self.token.clone(),
+            jobs: self.jobs,
+            relocate: false,
+            normalization: self.normalization,
+            target_fs: self.target_fs,
+            confirm: false,
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+            budget: self.budget,
+        }
+    }
+
+    fn settings(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                self.rewrites
+                    .iter()
+                    .map(|rule| rule.to_string())
+                    .collect::<Vec<String>>(),
+                &self.rewrite_from,
+                &self.rewrite_to,
+                self.template.as_ref().map(|template| template.to_string()),
+                self.path_style,
+                &self.drive,
+                self.normalization,
+                self.target_fs,
+            )
+        )
+    }
+
+    fn selects_playlists(&self) -> bool {
+        self.all
+            || self.playlist_type.is_some()
+            || self.smart
+            || self.no_smart
+            || self.title_glob.is_some()
+    }
+
+    fn playlist_filter(&self) -> PlaylistFilter {
+        PlaylistFilter {
+            only_playlist_type: self.playlist_type.clone(),
+            smart: match (self.smart, self.no_smart) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            title_glob: self.title_glob.clone(),
+            excludes: self.exclude.clone(),
+        }
+    }
+
+    fn with_profile(self, profile: &Profile) -> Self {
+        let rating_keys = if self.rating_keys.is_empty() && !self.selects_playlists() {
+            profile.playlists.clone()
+        } else {
+            self.rating_keys
+        };
+        SyncArguments {
+            rating_keys,
+            path: self.path.or(profile.path.clone()),
+            server: self.server.or(profile.server.clone()),
+            token: self.token.or(profile.server_token()),
+            rewrite_from: self.rewrite_from.or(profile.rewrite_from.clone()),
+            rewrite_to: self.rewrite_to.or(profile.rewrite_to.clone()),
+            rewrites: rewrites_or_profile(self.rewrites, profile),
+            template: self.template.or_else(|| profile_template(profile)),
+            budget: self.budget.or_else(|| profile_budget(profile)),
+            ..self
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let args = Cli::parse();
     configure_logger(&args);
 
+    let config_file = ConfigFile::new(args.config.clone(), args.dry_run);
+    let mut config = match config_file.load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Could not load configuration: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let profile_name = args.profile.as_deref().unwrap_or(config::DEFAULT_PROFILE);
+    let profile = config.profile(profile_name);
+    let dry_run = args.dry_run;
+
     match args.command {
         Some(Command::ListPlaylists(list_playlists_arguments)) => {
-            let plex_client = PlexClient::new(
-                list_playlists_arguments.server.clone(),
-                list_playlists_arguments.token.clone(),
+            let list_playlists_arguments = list_playlists_arguments.with_profile(&profile);
+            let plex_client = plex_client(
+                &list_playlists_arguments.server,
+                &list_playlists_arguments.token,
             );
-            list_playlists(plex_client, list_playlists_arguments)
+            exit_code(list_playlists(plex_client, list_playlists_arguments))
         }
         Some(Command::GetPlaylist(get_playlist_arguments)) => {
-            let plex_client = PlexClient::new(
-                get_playlist_arguments.server.clone(),
-                get_playlist_arguments.token.clone(),
+            let get_playlist_arguments = get_playlist_arguments.with_profile(&profile);
+            let plex_client = plex_client(
+                &get_playlist_arguments.server,
+                &get_playlist_arguments.token,
             );
-            get_playlist(plex_client, get_playlist_arguments)
+            exit_code(get_playlist(plex_client, get_playlist_arguments))
         }
         Some(Command::DumpPlaylist(dump_playlist_arguments)) => {
-            let plex_client = PlexClient::new(
-                dump_playlist_arguments.server.clone(),
-                dump_playlist_arguments.token.clone(),
+            let dump_playlist_arguments = dump_playlist_arguments.with_profile(&profile);
+            let plex_client = plex_client(
+                &dump_playlist_arguments.server,
+                &dump_playlist_arguments.token,
+            );
+            exit_code(
+                dump_playlist(
+                    plex_client,
+                    dump_playlist_arguments,
+                    &mut BTreeMap::new(),
+                    dry_run,
+                )
+                .map(|_| ()),
+            )
+        }
+        Some(Command::VerifyM3u(verify_m3u_arguments)) => {
+            let verify_m3u_arguments = verify_m3u_arguments.with_profile(&profile);
+            let root_path = verify_m3u_arguments.root_path();
+            let mut manifest = if Manifest::exists(&root_path) {
+                match Manifest::load(&root_path) {
+                    Ok(manifest) => Some(manifest),
+                    Err(error) => {
+                        eprintln!("Error: could not read manifest: {}", error);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            } else {
+                None
+            };
+            exit_code(
+                verify_m3u(verify_m3u_arguments, manifest.as_mut(), &mut None, dry_run).map(|_| ()),
+            )
+        }
+        Some(Command::Sync(sync_arguments)) => sync(sync_arguments.with_profile(&profile), dry_run),
+        Some(Command::Why(why_arguments)) => why(why_arguments.with_profile(&profile)),
+        Some(Command::Config(config_arguments)) => match config_command(
+            config_file,
+            config,
+            args.profile.as_deref(),
+            config_arguments,
+        ) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                ExitCode::FAILURE
+            }
+        },
+        Some(Command::Login(login_arguments)) => {
+            let profile_name = profile_name.to_string();
+            let plex_tv_client = PlexTvClient::new(
+                login_arguments.plex_tv_url.clone(),
+                client_identifier(&config_file, &mut config),
+                None,
+            );
+            match login(plex_tv_client, login_arguments) {
+                Ok(token) => {
+                    config.profile_mut(&profile_name).token = Some(token);
+                    match config_file.store(&config) {
+                        Ok(()) => {
+                            println!(
+                                "{} in profile {}",
+                                if dry_run {
+                                    "Would save token"
+                                } else {
+                                    "Token saved"
+                                },
+                                profile_name
+                            );
+                            ExitCode::SUCCESS
+                        }
+                        Err(error) => {
+                            eprintln!("Error: {}", error);
+                            ExitCode::FAILURE
+                        }
+                    }
+                }
+                Err(error) => exit_code(Err(error)),
+            }
+        }
+        Some(Command::Servers(servers_arguments)) => {
+            let servers_arguments = servers_arguments.with_profile(&profile);
+            let plex_tv_client = PlexTvClient::new(
+                servers_arguments.plex_tv_url.clone(),
+                client_identifier(&config_file, &mut config),
+                servers_arguments.token.clone(),
+            );
+            let save = servers_arguments.save.clone();
+            let (servers, discovery_failed) = servers(plex_tv_client, servers_arguments);
+            match save {
+                None if discovery_failed => ExitCode::FAILURE,
+                None => ExitCode::SUCCESS,
+                Some(name) => match servers
+                    .iter()
+                    .find(|server| server.matches(&name))
+                    .map(|server| (server.best_connection(), &server.access_token))
+                {
+                    Some((None, _)) => {
+                        eprintln!("Error: no reachable connection to {} was found", name);
+                        ExitCode::FAILURE
+                    }
+                    Some((Some(connection), access_token)) => {
+                        let profile = config.profile_mut(profile_name);
+                        profile.server = Some(connection.uri.clone());
+                        profile.server_token = access_token.clone();
+                        match config_file.store(&config) {
+                            Ok(()) => {
+                                println!(
+                                    "{} {} in profile {}",
+                                    if dry_run { "Would save" } else { "Saved" },
+                                    connection.uri,
+                                    profile_name
+                                );
+                                ExitCode::SUCCESS
+                            }
+                            Err(error) => {
+                                eprintln!("Error: {}", error);
+                                ExitCode::FAILURE
+                            }
+                        }
+                    }
+                    None => {
+                        eprintln!("Error: no server named {} was found", name);
+                        ExitCode::FAILURE
+                    }
+                },
+            }
+        }
+        None => {
+            error!("No command provided");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn required<T: Clone>(value: &Option<T>, argument: &str) -> T {
+    match value {
+        Some(value) => value.clone(),
+        None => Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("`{}` must be provided or set in the profile", argument),
+            )
+            .exit(),
+    }
+}
+
+fn rewrites_or_profile(rewrites: Vec<RewriteRule>, profile: &Profile) -> Vec<RewriteRule> {
+    if !rewrites.is_empty() {
+        return rewrites;
+    }
+    profile
+        .rewrites
+        .iter()
+        .map(|rule| match rule.parse::<RewriteRule>() {
+            Ok(rule) => rule,
+            Err(message) => Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("profile rewrites: {}", message),
+                )
+                .exit(),
+        })
+        .collect()
+}
+
+fn profile_template(profile: &Profile) -> Option<PathTemplate> {
+    profile
+        .template
+        .as_ref()
+        .map(|template| match template.parse::<PathTemplate>() {
+            Ok(template) => template,
+            Err(message) => Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("profile template: {}", message),
+                )
+                .exit(),
+        })
+}
+
+fn profile_budget(profile: &Profile) -> Option<u64> {
+    profile
+        .budget
+        .as_ref()
+        .map(|budget| match capacity::parse_size(budget) {
+            Ok(budget) => budget,
+            Err(message) => Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("profile budget: {}", message),
+                )
+                .exit(),
+        })
+}
+
+fn client_identifier(config_file: &ConfigFile, config: &mut Config) -> String {
+    let generated = config.client_identifier.is_none();
+    let client_identifier = config.client_identifier();
+    if generated && let Err(error) = config_file.store(config) {
+        warn!("Could not save client identifier: {}", error);
+    }
+    client_identifier
+}
+
+fn plex_client(server: &Option<String>, token: &Option<String>) -> PlexClient {
+    PlexClient::new(required(server, "--server"), token.clone())
+}
+
+fn exit_code<E: fmt::Display>(result: Result<(), E>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {}", logger::redact(error.to_string()));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn config_command(
+    config_file: ConfigFile,
+    mut config: Config,
+    profile_name: Option<&str>,
+    arguments: ConfigArguments,
+) -> Result<(), ConfigError> {
+    match arguments.command {
+        ConfigCommand::Show => {
+            println!("Configuration file: {:?}", config_file.location()?);
+            for (name, profile) in config.profiles.iter() {
+                if profile_name.is_none_or(|profile_name| profile_name == name) {
+                    println!("\n[{}]", name);
+                    for (key, value) in profile.informations() {
+                        if let Some(inner_value) = value {
+                            println!("{}: {}", key, inner_value)
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        ConfigCommand::Set(ConfigSetArguments { key, values }) => {
+            let profile_name = profile_name.unwrap_or(config::DEFAULT_PROFILE);
+            config.profile_mut(profile_name).set(&key, values)?;
+            config_file.store(&config)?;
+            println!(
+                "{} {} in profile {}",
+                if config_file.dry_run {
+                    "Would update"
+                } else {
+                    "Updated"
+                },
+                key,
+                profile_name
             );
-            dump_playlist(plex_client, dump_playlist_arguments);
-        }
-        Some(Command::VerifyM3u(verify_m3u_arguments)) => verify_m3u(verify_m3u_arguments),
-        Some(Command::Sync(sync_arguments)) => {
-            let plex_client =
-                PlexClient::new(sync_arguments.server.clone(), sync_arguments.token.clone());
-            for rating_key in sync_arguments.rating_keys {
-                let destination_file = dump_playlist(
-                    plex_client.clone(),
-                    DumpPlaylistArguments {
-                        server: sync_arguments.server.clone(),
-                        token: sync_arguments.token.clone(),
-                        rating_key: rating_key.clone(),
-                        rewrite_from: sync_arguments.rewrite_from.clone(),
-                        rewrite_to: sync_arguments.rewrite_to.clone(),
-                        file: Some(sync_arguments.path.clone()),
-                        stdout: false,
-                    },
+            Ok(())
+        }
+    }
+}
+
+fn login(plex_tv_client: PlexTvClient, arguments: LoginArguments) -> Result<String, PlexError> {
+    let pin = plex_tv_client.create_pin()?;
+    println!(
+        "Go to {} and enter the code {} to authorize plexm3u",
+        plex_tv_client.link_url(),
+        pin.code
+    );
+    plex_tv_client.wait_for_token(
+        &pin,
+        Duration::from_secs(arguments.timeout),
+        LOGIN_POLL_INTERVAL,
+    )
+}
+
+fn servers(plex_tv_client: PlexTvClient, arguments: ServersArguments) -> (Vec<Server>, bool) {
+    let mut discovery_failed = false;
+    let (resources, listed_account) = match arguments.token {
+        Some(_) => match plex_tv_client.resources() {
+            Ok(resources) => (resources, true),
+            Err(error) => {
+                eprintln!(
+                    "Could not list account servers: {}",
+                    logger::redact(error.to_string())
+                );
+                discovery_failed = true;
+                (vec![], false)
+            }
+        },
+        None => {
+            println!("No token provided, only looking for local servers");
+            (vec![], false)
+        }
+    };
+    let gdm_servers = if arguments.no_gdm {
+        vec![]
+    } else {
+        match gdm::discover(
+            &arguments.gdm_address,
+            arguments.gdm_port,
+            Duration::from_millis(arguments.gdm_timeout),
+        ) {
+            Ok(gdm_servers) => gdm_servers,
+            Err(error) if listed_account => {
+                warn!(
+                    "Could not discover local servers, only showing account servers: {}",
+                    error
                 );
-                match destination_file {
-                    Some(file) => verify_m3u(VerifyM3uArguments {
-                        file: file,
-                        path: None,
-                        fix: sync_arguments.fix,
-                        server: Some(sync_arguments.server.clone()),
-                        token: sync_arguments.token.clone(),
-                    }),
-                    None => panic!("Error occured dumping rating key {}", rating_key.clone()),
+                vec![]
+            }
+            Err(error) => {
+                eprintln!("Could not discover local servers: {}", error);
+                discovery_failed = true;
+                vec![]
+            }
+        }
+    };
+
+    let mut servers = server::merge(resources, gdm_servers);
+    if !arguments.no_check {
+        server::check_reachability(&mut servers, Duration::from_millis(arguments.check_timeout));
+    }
+    println!("Found {} servers", servers.len());
+    for server in servers.iter() {
+        server.print_informations();
+    }
+    (servers, discovery_failed)
+}
+
+fn sync(sync_arguments: SyncArguments, dry_run: bool) -> ExitCode {
+    let plex_client = plex_client(&sync_arguments.server, &sync_arguments.token);
+    let path = required(&sync_arguments.path, "--path");
+    let mut manifest = match Manifest::load(Path::new(&path)) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            eprintln!("Error: could not read manifest: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let playlists = match plex_client.list_playlists() {
+        Ok(container) => container.playlists,
+        Err(error) if sync_arguments.selects_playlists() || !sync_arguments.exclude.is_empty() => {
+            eprintln!(
+                "Error: could not list playlists: {}",
+                logger::redact(error.to_string())
+            );
+            return ExitCode::FAILURE;
+        }
+        Err(error) => {
+            warn!("Could not list playlists, syncing all of them: {}", error);
+            vec![]
+        }
+    };
+    let filter = sync_arguments.playlist_filter();
+    let mut rating_keys = sync_arguments.rating_keys.clone();
+    if sync_arguments.selects_playlists() {
+        for playlist in playlists
+            .iter()
+            .filter(|playlist| playlist.matches(&filter))
+        {
+            if !rating_keys.contains(&playlist.rating_key) {
+                rating_keys.push(playlist.rating_key.clone());
+            }
+        }
+    }
+    rating_keys.retain(|rating_key| {
+        match playlists
+            .iter()
+            .find(|playlist| playlist.rating_key == *rating_key)
+        {
+            Some(playlist) => !playlist.is_excluded(&filter),
+            None => !filter.excludes.contains(rating_key),
+        }
+    });
+    if rating_keys.is_empty() {
+        eprintln!("Error: no playlist to sync");
+        return ExitCode::from(NO_PLAYLIST_EXIT_CODE);
+    }
+    let server_playlists: HashMap<String, Playlist> = playlists
+        .into_iter()
+        .map(|playlist| (playlist.rating_key.clone(), playlist))
+        .collect();
+    let settings = sync_arguments.settings();
+    let mut failures = vec![];
+    let mut budget = None;
+    let mut references = References::default();
+    for rating_key in rating_keys.iter() {
+        let server_playlist = server_playlists.get(rating_key);
+        let updated_at = server_playlist.and_then(|playlist| playlist.updated_at);
+        let synced = if let Some(entry) = server_playlist
+            .and_then(|playlist| manifest.is_unchanged(playlist, &settings))
+            .filter(|_| !sync_arguments.full)
+            .filter(|entry| {
+                match has_same_files(
+                    &plex_client,
+                    rating_key,
+                    sync_arguments.page_size,
+                    &entry.file,
+                ) {
+                    Ok(true) => true,
+                    Ok(false) => {
+                        println!("Files of {} changed on the server", entry.title);
+                        false
+                    }
+                    Err(error) => {
+                        warn!(
+                            "Could not compare {} with the server: {}",
+                            entry.title, error
+                        );
+                        false
+                    }
+                }
+            }) {
+            println!("{} is unchanged since last sync", entry.title);
+            let file = entry.file.clone();
+            verify_m3u(
+                sync_arguments.verify_m3u_arguments(file.clone()),
+                Some(&mut manifest),
+                &mut budget,
+                dry_run,
+            )
+            .map(|m3u| (file, m3u))
+        } else {
+            let destination_file = dump_playlist(
+                plex_client.clone(),
+                DumpPlaylistArguments {
+                    server: sync_arguments.server.clone(),
+                    token: sync_arguments.token.clone(),
+                    rating_key: rating_key.clone(),
+                    rewrite_from: sync_arguments.rewrite_from.clone(),
+                    rewrite_to: sync_arguments.rewrite_to.clone(),
+                    rewrites: sync_arguments.rewrites.clone(),
+                    template: sync_arguments.template.clone(),
+                    path_style: sync_arguments.path_style,
+                    track_root: None,
+                    drive: sync_arguments.drive.clone(),
+                    file: Some(path.clone()),
+                    stdout: false,
+                    page_size: sync_arguments.page_size,
+                    normalization: sync_arguments.normalization,
+                    target_fs: sync_arguments.target_fs,
+                },
+                &mut manifest.claimed_paths,
+                dry_run,
+            );
+            match destination_file {
+                Ok(Some((file, m3u))) => {
+                    manifest.record_playlist(
+                        rating_key,
+                        PlaylistEntry {
+                            title: server_playlist
+                                .map(|playlist| playlist.title.clone())
+                                .unwrap_or(rating_key.clone()),
+                            file: file.clone(),
+                            updated_at,
+                            settings: settings.clone(),
+                        },
+                    );
+                    verify_tracks(
+                        sync_arguments.verify_m3u_arguments(file.clone()),
+                        m3u,
+                        Some(&mut manifest),
+                        &mut budget,
+                        dry_run,
+                    )
+                    .map(|m3u| (file, m3u))
                 }
+                Ok(None) => unreachable!("sync always dumps to --path"),
+                Err(error) => Err(error),
+            }
+        };
+        match synced {
+            Ok((file, m3u)) => {
+                let arguments = sync_arguments.verify_m3u_arguments(file);
+                references.add_playlist(
+                    rating_key,
+                    Path::new(&arguments.file),
+                    &m3u.tracks,
+                    &arguments.root_path(),
+                    &arguments.path_options(),
+                );
+            }
+            Err(error) => {
+                let error = logger::redact(error.to_string());
+                eprintln!("Could not sync playlist {}: {}", rating_key, error);
+                failures.push((rating_key, error));
+            }
+        }
+    }
+
+    if sync_arguments.prune {
+        if failures.is_empty() {
+            references.add_files(manifest.referenced_files(&rating_keys));
+            for removed in prune(Path::new(&path), &references, dry_run) {
+                manifest.forget(&removed);
+            }
+        } else {
+            println!(
+                "\nNot pruning {:?}, {} playlists failed to sync",
+                path,
+                failures.len()
+            );
+        }
+    }
+    if !dry_run && let Err(error) = manifest.save() {
+        eprintln!("Could not save {:?}: {}", manifest.location(), error);
+    }
+
+    if failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        eprintln!(
+            "\n{} / {} playlists failed to sync:",
+            failures.len(),
+            rating_keys.len()
+        );
+        for (rating_key, error) in failures {
+            eprintln!("- {}: {}", rating_key, error);
+        }
+        ExitCode::FAILURE
+    }
+}
+
+fn why(arguments: WhyArguments) -> ExitCode {
+    let root_path = required(&arguments.path, "--path");
+    let manifest = match Manifest::load(Path::new(&root_path)) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            eprintln!("Error: could not read manifest: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let full_path =
+        paths::locate(Path::new(&arguments.file)).unwrap_or_else(|| PathBuf::from(&arguments.file));
+    let Some(entry) = manifest.file(&full_path) else {
+        println!(
+            "{} is not in the manifest at {:?}",
+            arguments.file,
+            manifest.location()
+        );
+        return ExitCode::FAILURE;
+    };
+
+    println!("{}", manifest.key(&full_path));
+    println!("Part key: {}", entry.part_key);
+    if let Some(size) = entry.size {
+        println!("Size: {} bytes", size);
+    }
+    if let Some(updated_at) = entry.updated_at {
+        println!("Updated at: {}", updated_at);
+    }
+    if entry.playlists.is_empty() {
+        println!("Not referenced by any synced playlist");
+    } else {
+        println!("Playlists:");
+        for playlist in entry.playlists.iter() {
+            match manifest.playlists.get(playlist) {
+                Some(playlist_entry) => println!("- {} ({})", playlist_entry.title, playlist),
+                None => println!("- {}", playlist),
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn prune(root_path: &Path, references: &References, dry_run: bool) -> Vec<PathBuf> {
+    let orphans = match prune::find_orphans(root_path, references) {
+        Ok(orphans) => orphans,
+        Err(error) => {
+            eprintln!("\nCould not prune {:?}: {}", root_path, error);
+            return vec![];
+        }
+    };
+    if orphans.is_empty() {
+        println!("\nNo unreferenced files under {:?}", root_path);
+        return vec![];
+    }
+
+    println!(
+        "\n{} unreferenced files under {:?}:",
+        if dry_run { "Would remove" } else { "Removing" },
+        root_path
+    );
+    let mut removed = vec![];
+    let mut removed_size = 0;
+    for orphan in orphans.iter() {
+        println!("- {:?} ({} bytes)", orphan.path, orphan.size);
+        if dry_run {
+            continue;
+        }
+        match fs::remove_file(&orphan.path) {
+            Ok(_) => {
+                removed.push(orphan.path.clone());
+                removed_size += orphan.size;
+            }
+            Err(error) => eprintln!("\tCould not remove {:?}: {}", orphan.path, error),
+        }
+    }
+    if dry_run {
+        let total_size: u64 = orphans.iter().map(|orphan| orphan.size).sum();
+        println!(
+            "Would remove {} files ({} bytes)",
+            orphans.len(),
+            total_size
+        );
+        return vec![];
+    }
+    match prune::remove_empty_directories(root_path) {
+        Ok(directories) => {
+            for directory in directories {
+                println!("Removed empty directory {:?}", directory);
             }
         }
-        None => error!("No command provided"),
+        Err(error) => eprintln!("Could not remove empty directories: {}", error),
     }
+    println!("Removed {} files ({} bytes)", removed.len(), removed_size);
+    removed
 }
 
-fn verify_m3u(arguments: VerifyM3uArguments) {
-    let root_path = arguments
-        .path
-        .as_deref()
-        .map(Path::new)
-        .unwrap_or_else(|| Path::new(&arguments.file).parent().unwrap());
+fn verify_m3u(
+    arguments: VerifyM3uArguments,
+    manifest: Option<&mut Manifest>,
+    budget: &mut Option<Budget>,
+    dry_run: bool,
+) -> Result<M3U, CommandError> {
+    let read_m3u = m3u::read(&arguments.file)
+        .map_err(|error| CommandError::Read(arguments.file.clone(), error))?;
+    verify_tracks(arguments, read_m3u, manifest, budget, dry_run)
+}
+
+fn verify_tracks(
+    arguments: VerifyM3uArguments,
+    mut read_m3u: M3U,
+    manifest: Option<&mut Manifest>,
+    budget: &mut Option<Budget>,
+    dry_run: bool,
+) -> Result<M3U, CommandError> {
+    let root_path = arguments.root_path();
+    let root_path = root_path.as_path();
 
     let should_fix = match (arguments.server.clone(), arguments.fix) {
         (Some(_), true) => true,
-        (None, true) => {
-            panic!("Must provide `--server` (and maybe `--token`) to be able to fix missing tracks")
-        }
+        (None, true) => Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "`--fix` requires `--server` (and maybe `--token`) to download missing tracks",
+            )
+            .exit(),
         _ => false,
     };
 
-    let read_m3u = m3u::read(&arguments.file);
     let mut missing_tracks = vec![];
+    let mut stale_count = 0;
+    let mut upgraded_count = 0;
     let mut total_count = 0;
-    match read_m3u {
-        Ok(M3U { tracks, .. }) => {
-            for track in tracks.iter() {
-                total_count += 1;
-                if !track.exists_at(root_path) {
+    let mut pending_paths = HashSet::new();
+    let mut space_error = None;
+    for track in read_m3u.tracks.iter() {
+        total_count += 1;
+        match track.state_at(root_path, &arguments.path_options()) {
+            TrackState::Present => {
+                if manifest.as_ref().is_some_and(|manifest| {
+                    manifest.is_upgraded(track, &track.full_path(root_path))
+                }) {
+                    println!("- {} (upgraded on server)", track.path);
+                    upgraded_count += 1;
+                    pending_paths.insert(track.path.clone());
                     missing_tracks.push(track.clone());
                 }
             }
+            TrackState::Missing => {
+                println!("- {}", track.path);
+                missing_tracks.push(track.clone());
+            }
+            TrackState::Stale { expected, actual } => {
+                println!(
+                    "- {} (stale: expected {} bytes, found {})",
+                    track.path, expected, actual
+                );
+                stale_count += 1;
+                missing_tracks.push(track.clone());
+            }
+        }
+    }
+
+    if arguments.relocate && !missing_tracks.is_empty() {
+        let remaining_tracks = relocate_missing_tracks(
+            &mut read_m3u,
+            &missing_tracks,
+            root_path,
+            &arguments.path_options(),
+        )?;
+        if remaining_tracks.len() != missing_tracks.len() {
+            write_m3u(Path::new(&arguments.file), &read_m3u, dry_run)
+                .map_err(|error| CommandError::Write(arguments.file.clone(), error))?;
         }
-        Err(error) => panic!("Could not read {}: {}", arguments.file, error),
+        missing_tracks = remaining_tracks;
     }
 
     if missing_tracks.is_empty() {
         println!("All tracks ({}) exists", total_count)
     } else {
-        let missing_track_count = missing_tracks.len();
-        for track in missing_tracks {
-            println!("- {}", track.path);
-            if should_fix {
-                println!("\tDownloading...");
-                let plex_client =
-                    PlexClient::new(arguments.server.clone().unwrap(), arguments.token.clone());
-                if !download_part(plex_client, track.clone(), root_path) {
-                    println!("\tCould not download {}", track.path.clone());
+        let missing_track_count = missing_tracks.len() - stale_count - upgraded_count;
+        if should_fix {
+            let plex_client = plex_client(&arguments.server, &arguments.token);
+            let (missing_tracks, resolved_count) =
+                resolve_missing_tracks(&plex_client, &mut read_m3u, missing_tracks, &arguments);
+            if resolved_count > 0 {
+                write_m3u(Path::new(&arguments.file), &read_m3u, dry_run)
+                    .map_err(|error| CommandError::Write(arguments.file.clone(), error))?;
+            }
+            match fit_in_budget(missing_tracks, root_path, &arguments, budget) {
+                Err(error) => space_error = Some(error),
+                Ok(missing_tracks) if dry_run => {
+                    plan_downloads(&missing_tracks, root_path, &arguments.path_options())
+                }
+                Ok(missing_tracks) => {
+                    println!("\nDownloading {} tracks...", missing_tracks.len());
+                    let summary = download::download_all(
+                        &plex_client,
+                        missing_tracks,
+                        root_path,
+                        &arguments.path_options(),
+                        arguments.jobs,
+                        |result| match &result.outcome {
+                            Ok(full_path) => {
+                                println!("\tCreated {:?}", full_path);
+                                pending_paths.remove(&result.item.path);
+                            }
+                            Err(error) => {
+                                eprintln!(
+                                    "\tCould not download {}: {}",
+                                    result.item.path,
+                                    logger::redact(error.to_string())
+                                )
+                            }
+                        },
+                    );
+                    println!("\n{}", summary);
                 }
             }
         }
@@ -210,117 +1224,452 @@ fn verify_m3u(arguments: VerifyM3uArguments) {
             "\nMissing tracks at {:?}: {} / {}",
             root_path, missing_track_count, total_count,
         );
+        if stale_count > 0 {
+            println!("Stale tracks: {} / {}", stale_count, total_count);
+        }
+        if upgraded_count > 0 {
+            println!("Upgraded tracks: {} / {}", upgraded_count, total_count);
+        }
+    }
+
+    if let Some(manifest) = manifest
+        && !dry_run
+    {
+        record_m3u(
+            manifest,
+            &read_m3u,
+            &arguments.file,
+            root_path,
+            &pending_paths,
+        );
+    }
+
+    match space_error {
+        Some(error) => Err(error.into()),
+        None => Ok(read_m3u),
     }
 }
 
-fn download_part(plex_client: PlexClient, track: Item, root_path: &Path) -> bool {
-    match track.track_key() {
-        Some(key) => {
-            let mut response = plex_client.get_part(key);
-            let full_path = track.full_path(root_path);
-            fs::create_dir_all(full_path.parent().unwrap()).expect("Folder could not be created");
-            let mut out = File::create(full_path.clone()).expect("File could not be created");
-            match io::copy(&mut response, &mut out) {
-                Ok(_) => {
-                    println!("\tCreated {:?}", full_path);
-                    true
+fn fit_in_budget(
+    tracks: Vec<Item>,
+    root_path: &Path,
+    arguments: &VerifyM3uArguments,
+    budget: &mut Option<Budget>,
+) -> Result<Vec<Item>, NotEnoughSpace> {
+    if budget.is_none() {
+        match Budget::measure(root_path, arguments.budget) {
+            Ok(measured) => *budget = Some(measured),
+            Err(error) => {
+                warn!("Could not measure free space at {:?}: {}", root_path, error);
+                return Ok(tracks);
+            }
+        }
+    }
+    let Some(budget) = budget.as_mut() else {
+        return Ok(tracks);
+    };
+
+    let fit = budget.fit(tracks, &arguments.path_options())?;
+    if !fit.skipped.is_empty() {
+        let skipped_size: u64 = fit.skipped.iter().map(|(_, size)| size).sum();
+        println!(
+            "\nSkipping {} tracks ({}) that do not fit in the budget ({} left):",
+            fit.skipped.len(),
+            capacity::format_size(skipped_size),
+            capacity::format_size(budget.remaining())
+        );
+        for (track, size) in fit.skipped.iter() {
+            println!("- {} ({} bytes)", track.path, size);
+        }
+    }
+    Ok(fit.tracks)
+}
+
+fn plan_downloads(tracks: &[Item], root_path: &Path, path_options: &PathOptions) {
+    println!("\nWould download {} tracks:", tracks.len());
+    let mut total_size = 0;
+    let mut unknown_count = 0;
+    for track in tracks.iter() {
+        match download::remaining_size(track, root_path, path_options) {
+            Some(size) => {
+                println!("- {} ({} bytes)", track.path, size);
+                total_size += size;
+            }
+            None => {
+                println!("- {} (unknown size)", track.path);
+                unknown_count += 1;
+            }
+        }
+    }
+    if unknown_count > 0 {
+        println!(
+            "Would download {} bytes, plus {} tracks of unknown size",
+            total_size, unknown_count
+        );
+    } else {
+        println!("Would download {} bytes", total_size);
+    }
+}
+
+fn write_m3u(file: &Path, m3u: &M3U, dry_run: bool) -> io::Result<()> {
+    if !dry_run {
+        println!("Writing {:?}", file);
+        return m3u::write(file, m3u);
+    }
+    let mut content = vec![];
+    m3u::write_to(&mut content, m3u)?;
+    match fs::read(file) {
+        Ok(existing) if existing == content => println!("{:?} is unchanged", file),
+        Ok(_) => println!("Would update {:?}", file),
+        Err(_) => println!("Would create {:?}", file),
+    }
+    Ok(())
+}
+
+fn record_m3u(
+    manifest: &mut Manifest,
+    m3u: &M3U,
+    file: &str,
+    root_path: &Path,
+    pending_paths: &HashSet<String>,
+) {
+    let playlist = m3u
+        .metadata
+        .iter()
+        .find_map(|metadata| match metadata {
+            m3u::Metadata::RatingKey(rating_key) => Some(rating_key.to_string()),
+            _ => None,
+        })
+        .unwrap_or_else(|| file.to_string());
+    manifest.record_tracks(&playlist, &m3u.tracks, root_path, pending_paths);
+    if let Err(error) = manifest.save() {
+        eprintln!("Could not save {:?}: {}", manifest.location(), error);
+    }
+}
+
+fn relocate_missing_tracks(
+    m3u: &mut M3U,
+    missing_tracks: &[Item],
+    root_path: &Path,
+    path_options: &PathOptions,
+) -> Result<Vec<Item>, CommandError> {
+    let relocator = Relocator::scan(root_path)
+        .map_err(|error| CommandError::Scan(root_path.to_path_buf(), error))?;
+
+    println!("\nRelocating {} tracks...", missing_tracks.len());
+    let mut remaining_tracks = vec![];
+    let mut relocated_count = 0;
+    for track in missing_tracks.iter() {
+        if track.local_path(root_path, path_options).exists() {
+            remaining_tracks.push(track.clone());
+            continue;
+        }
+        println!("- {}", track.path);
+        match relocator.relocate(track) {
+            Relocation::Found(path) => {
+                let new_path = if Path::new(&track.path).is_absolute() {
+                    root_path.join(path)
+                } else {
+                    path
+                }
+                .to_string_lossy()
+                .to_string();
+                println!("\tFound at {}", new_path);
+                for item in m3u.tracks.iter_mut().filter(|item| item.path == track.path) {
+                    item.path = new_path.clone();
                 }
-                Err(error) => {
-                    eprintln!("\tError occurred while copying {}", error);
-                    false
+                relocated_count += 1;
+            }
+            Relocation::Ambiguous(paths) => {
+                println!("\tSeveral candidates, leaving as is:");
+                for path in paths {
+                    println!("\t\t{}", path.to_string_lossy());
                 }
+                remaining_tracks.push(track.clone());
+            }
+            Relocation::NotFound => {
+                println!("\tNo candidate found");
+                remaining_tracks.push(track.clone());
             }
         }
-        None => false,
     }
+    println!(
+        "Relocated {} / {} tracks",
+        relocated_count,
+        missing_tracks.len()
+    );
+
+    Ok(remaining_tracks)
 }
 
-fn dump_playlist(plex_client: PlexClient, arguments: DumpPlaylistArguments) -> Option<String> {
+fn resolve_missing_tracks(
+    plex_client: &PlexClient,
+    m3u: &mut M3U,
+    missing_tracks: Vec<Item>,
+    arguments: &VerifyM3uArguments,
+) -> (Vec<Item>, usize) {
+    let (mut tracks, unkeyed_tracks): (Vec<Item>, Vec<Item>) = missing_tracks
+        .into_iter()
+        .partition(|track| track.track_key().is_some());
+    if unkeyed_tracks.is_empty() {
+        return (tracks, 0);
+    }
+
+    println!(
+        "\nResolving {} tracks without track key...",
+        unkeyed_tracks.len()
+    );
+    let mut resolved_count = 0;
+    for track in unkeyed_tracks.iter() {
+        println!("- {}", track.path);
+        let Some(query) = resolver::Query::from_item(track) else {
+            println!("\tNothing to search for");
+            continue;
+        };
+        let candidate = match resolver::resolve(plex_client, &query) {
+            Ok(resolver::Resolution::Match(candidate)) => *candidate,
+            Ok(resolver::Resolution::Ambiguous(candidates)) => {
+                println!(
+                    "\tSkipping, {} tracks match {} equally:",
+                    candidates.len(),
+                    query
+                );
+                for candidate in candidates.iter() {
+                    println!("\t\t{}", candidate);
+                }
+                continue;
+            }
+            Ok(resolver::Resolution::NoMatch) => {
+                println!("\tNo match for {}", query);
+                continue;
+            }
+            Err(error) => {
+                eprintln!(
+                    "\tCould not search for {}: {}",
+                    query,
+                    logger::redact(error.to_string())
+                );
+                continue;
+            }
+        };
+        println!("\tMatched {}", candidate);
+        let confident = candidate.confidence >= arguments.min_confidence;
+        let accepted = if arguments.confirm {
+            confirm("\tDownload?", confident)
+        } else {
+            if !confident {
+                println!(
+                    "\tSkipping, confidence is below {}%",
+                    arguments.min_confidence
+                );
+            }
+            confident
+        };
+        if accepted {
+            resolved_count += 1;
+            let resolved_item = candidate.resolved_item(track);
+            for item in m3u.tracks.iter_mut().filter(|item| item.path == track.path) {
+                *item = resolved_item.clone();
+            }
+            tracks.push(resolved_item);
+        }
+    }
+    println!(
+        "Resolved {} / {} tracks without track key",
+        resolved_count,
+        unkeyed_tracks.len()
+    );
+
+    (tracks, resolved_count)
+}
+
+fn has_same_files(
+    plex_client: &PlexClient,
+    rating_key: &str,
+    page_size: u32,
+    file: &str,
+) -> Result<bool, CommandError> {
+    let known = m3u::read(file).map_err(|error| CommandError::Read(file.to_string(), error))?;
+    let mut current = vec![];
+    for page in plex_client.playlist_pages(rating_key.to_string(), page_size) {
+        current.extend(
+            page?
+                .track_files(&[], None)
+                .into_iter()
+                .map(|(_, track)| track),
+        );
+    }
+    Ok(manifest::has_same_files(&known.tracks, &current))
+}
+
+fn confirm(prompt: &str, default: bool) -> bool {
+    print!("{} {} ", prompt, if default { "[Y/n]" } else { "[y/N]" });
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        "y" | "yes" => true,
+        _ => false,
+    }
+}
+
+fn dump_playlist(
+    plex_client: PlexClient,
+    arguments: DumpPlaylistArguments,
+    claimed_paths: &mut BTreeMap<String, String>,
+    dry_run: bool,
+) -> Result<Option<(String, M3U)>, CommandError> {
     if let None = arguments.file
         && !arguments.stdout
     {
-        panic!("Requires at least `--file [FILE]` or `--stdout`")
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "requires at least `--file [FILE]` or `--stdout`",
+            )
+            .exit()
     }
-    let container = plex_client.get_playlist(arguments.rating_key.clone());
-    let tracks =
-        container.track_files(arguments.rewrite_from.clone(), arguments.rewrite_to.clone());
-    if arguments.stdout {
-        for track in tracks.clone() {
-            println!("{:?}", track);
+    let path_options = arguments.path_options();
+    let rewrite_rules = arguments.rewrite_rules();
+    let mut tracks = vec![];
+    let mut title = String::new();
+    let mut container_metadata = vec![];
+    let mut fetched = 0;
+    for page in plex_client.playlist_pages(arguments.rating_key.clone(), arguments.page_size) {
+        let container = page?;
+        fetched += container.fetched_count();
+        eprintln!(
+            "Fetched {} / {} items of {}",
+            fetched,
+            container.total_size.unwrap_or(fetched),
+            container.title
+        );
+        let mut page_tracks = container.track_files(&rewrite_rules, arguments.template.as_ref());
+        for (_, track) in page_tracks.iter_mut() {
+            track.path = path_options.apply(&track.path);
+        }
+        if arguments.stdout {
+            for (_, track) in page_tracks.iter() {
+                println!("{:?}", track);
+            }
+        }
+        tracks.extend(page_tracks);
+        title = container.title.clone();
+        container_metadata = container.metadata();
+    }
+    if arguments.template.is_some() {
+        let renamed = template::resolve_collisions(&mut tracks, claimed_paths);
+        if renamed > 0 {
+            eprintln!(
+                "Renamed {} tracks whose path collided with another",
+                renamed
+            );
         }
     }
+    let mut tracks: Vec<Item> = tracks.into_iter().map(|(_, track)| track).collect();
     if let Some(file) = arguments.file {
         let destination_folder = Path::new(&file);
 
         let destination_file = if destination_folder.is_dir() {
-            let destination_file = format!("{}.m3u", container.title);
+            let destination_file = format!("{}.m3u", title);
             Path::new(&destination_folder).join(destination_file)
         } else {
             destination_folder.to_path_buf()
         };
-        println!("Writing {:?}", destination_file);
-        let mut metadata = container.metadata();
-        if let Some(rewrite_from) = arguments.rewrite_from {
-            metadata.push(m3u::Metadata::RewriteFrom(rewrite_from.clone()))
+        if let Some(path_style) = arguments.path_style {
+            let playlist_directory = destination_file
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let track_root = arguments
+                .track_root
+                .as_deref()
+                .map(Path::new)
+                .unwrap_or(playlist_directory);
+            for track in tracks.iter_mut() {
+                track.path = path_style.format(
+                    &track.path,
+                    track_root,
+                    playlist_directory,
+                    arguments.drive.as_deref(),
+                );
+            }
         }
-        if let Some(rewrite_to) = arguments.rewrite_to {
-            metadata.push(m3u::Metadata::RewriteTo(rewrite_to.clone()))
+        let mut metadata = container_metadata;
+        match &arguments.template {
+            Some(template) => metadata.push(m3u::Metadata::Template(template.to_string())),
+            None => metadata.extend(rewrite_rules.into_iter().map(m3u::Metadata::Rewrite)),
         }
 
-        let m3u = M3U::new(tracks.clone(), metadata);
-        if let Err(error) = m3u::write(destination_file.clone(), m3u) {
-            panic!("Error writing {:?}: {}", destination_file, error);
-        }
-        destination_file.to_str().map(|str| str.to_string())
+        let m3u = M3U::new(tracks, metadata);
+        let Some(file) = destination_file.to_str().map(|str| str.to_string()) else {
+            return Err(CommandError::InvalidPath(destination_file));
+        };
+        write_m3u(&destination_file, &m3u, dry_run)
+            .map_err(|error| CommandError::Write(file.clone(), error))?;
+        Ok(Some((file, m3u)))
     } else {
-        None
+        Ok(None)
     }
 }
 
-fn get_playlist(plex_client: PlexClient, arguments: GetPlaylistArguments) {
-    let container = plex_client.get_playlist(arguments.rating_key);
-    let track_count = container.tracks.len();
-    let video_count = container.videos.len();
+fn get_playlist(plex_client: PlexClient, arguments: GetPlaylistArguments) -> Result<(), PlexError> {
+    let mut track_count = 0;
+    let mut video_count = 0;
+    for page in plex_client.playlist_pages(arguments.rating_key, arguments.page_size) {
+        let container = page?;
+        track_count += container.tracks.len();
+        video_count += container.videos.len();
 
-    if track_count > 0 {
-        println!("{} tracks", track_count);
         for track in container.tracks.iter() {
             track.print_informations();
         }
-    }
-
-    if video_count > 0 {
-        println!("{} videos", video_count);
         for video in container.videos.iter() {
             video.print_informations();
         }
     }
+
+    if track_count > 0 {
+        println!("\n{} tracks", track_count);
+    }
+    if video_count > 0 {
+        println!("\n{} videos", video_count);
+    }
+    Ok(())
 }
 
-fn list_playlists(plex_client: PlexClient, playlists_filter_arguments: PlaylistsFilterArguments) {
-    let container = plex_client.list_playlists();
+fn list_playlists(
+    plex_client: PlexClient,
+    playlists_filter_arguments: PlaylistsFilterArguments,
+) -> Result<(), PlexError> {
+    let container = plex_client.list_playlists()?;
     println!("Found {} playlists", container.size);
     for playlist in container.playlists {
         if playlist.matches(&to_playlist_filter(&playlists_filter_arguments)) {
-            println!("{}", playlist.to_string());
+            println!("{}", playlist);
         }
     }
+    Ok(())
 }
 
 fn to_playlist_filter(playlists_filter_arguments: &PlaylistsFilterArguments) -> PlaylistFilter {
     PlaylistFilter {
         only_playlist_type: playlists_filter_arguments.only.clone(),
+        smart: None,
+        title_glob: None,
+        excludes: vec![],
     }
 }
 
 fn configure_logger(args: &Cli) {
-    colog::init();
-
     let level = if args.verbose {
         LevelFilter::Trace
     } else {
         LevelFilter::Error
     };
 
-    log::set_max_level(level);
+    logger::init(level);
 }
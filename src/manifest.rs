@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::m3u::{Item, TrackState};
+use crate::paths::{self, PathOptions};
+use crate::plex_client::playlist::Playlist;
+
+const MANIFEST_FILE: &str = ".plexm3u-manifest.json";
+const PARTS_PREFIX: &str = "/library/parts/";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(skip)]
+    root_path: PathBuf,
+    #[serde(default)]
+    pub playlists: BTreeMap<String, PlaylistEntry>,
+    #[serde(default)]
+    pub files: BTreeMap<String, FileEntry>,
+    #[serde(default)]
+    pub claimed_paths: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    pub title: String,
+    pub file: String,
+    pub updated_at: Option<u64>,
+    #[serde(default)]
+    pub settings: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub part_key: String,
+    pub size: Option<u64>,
+    pub updated_at: Option<u64>,
+    pub playlists: BTreeSet<String>,
+}
+
+impl Manifest {
+    pub fn exists(root_path: &Path) -> bool {
+        root_path.join(MANIFEST_FILE).exists()
+    }
+
+    pub fn load(root_path: &Path) -> io::Result<Manifest> {
+        let mut manifest = match fs::read_to_string(root_path.join(MANIFEST_FILE)) {
+            Ok(content) => serde_json::from_str::<Manifest>(&content)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(error) => return Err(error),
+        };
+        manifest.root_path = root_path.to_path_buf();
+        Ok(manifest)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = self.root_path.join(MANIFEST_FILE);
+        let temporary_path = path.with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(temporary_path, path)
+    }
+
+    pub fn location(&self) -> PathBuf {
+        self.root_path.join(MANIFEST_FILE)
+    }
+
+    pub fn key(&self, full_path: &Path) -> String {
+        paths::relative_path(&self.root_path, full_path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn file(&self, full_path: &Path) -> Option<&FileEntry> {
+        self.files.get(&self.key(full_path))
+    }
+
+    pub fn is_upgraded(&self, track: &Item, full_path: &Path) -> bool {
+        match (self.file(full_path), track.track_key()) {
+            (Some(entry), Some(part_key)) => {
+                part_id(&entry.part_key) != part_id(&part_key)
+                    || matches!(
+                        (entry.size, track.size()),
+                        (Some(known), Some(current)) if known != current
+                    )
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_unchanged(&self, playlist: &Playlist, settings: &str) -> Option<&PlaylistEntry> {
+        if playlist.smart {
+            return None;
+        }
+        self.playlists
+            .get(&playlist.rating_key)
+            .filter(|entry| entry.updated_at.is_some() && entry.updated_at == playlist.updated_at)
+            .filter(|entry| entry.settings == settings)
+            .filter(|entry| Path::new(&entry.file).exists())
+    }
+
+    pub fn record_playlist(&mut self, rating_key: &str, entry: PlaylistEntry) {
+        self.playlists.insert(rating_key.to_string(), entry);
+    }
+
+    pub fn record_tracks(
+        &mut self,
+        playlist: &str,
+        tracks: &[Item],
+        tracks_root: &Path,
+        pending_paths: &HashSet<String>,
+    ) {
+        for entry in self.files.values_mut() {
+            entry.playlists.remove(playlist);
+        }
+        for track in tracks.iter() {
+            let key = self.key(&track.full_path(tracks_root));
+            if pending_paths.contains(&track.path) {
+                if let Some(entry) = self.files.get_mut(&key) {
+                    entry.playlists.insert(playlist.to_string());
+                }
+                continue;
+            }
+            let Some(part_key) = track.track_key() else {
+                continue;
+            };
+            if track.state_at(tracks_root, &PathOptions::default()) != TrackState::Present {
+                continue;
+            }
+            let mut playlists = self
+                .files
+                .remove(&key)
+                .map(|entry| entry.playlists)
+                .unwrap_or_default();
+            playlists.insert(playlist.to_string());
+            self.files.insert(
+                key,
+                FileEntry {
+                    part_key,
+                    size: track.size(),
+                    updated_at: track.updated_at(),
+                    playlists,
+                },
+            );
+        }
+        let gone: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(key, entry)| entry.playlists.is_empty() && !self.root_path.join(key).exists())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in gone.iter() {
+            self.files.remove(key);
+            self.release_claims(key);
+        }
+    }
+
+    pub fn referenced_files(&self, except_playlists: &[String]) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .playlists
+                    .iter()
+                    .any(|playlist| !except_playlists.contains(playlist))
+            })
+            .map(|(key, _)| self.root_path.join(key))
+            .collect()
+    }
+
+    pub fn forget(&mut self, full_path: &Path) {
+        let key = self.key(full_path);
+        self.files.remove(&key);
+        self.release_claims(&key);
+    }
+
+    /// Lets the next track templated to this file's path have it, without a suffix.
+    fn release_claims(&mut self, key: &str) {
+        self.claimed_paths = std::mem::take(&mut self.claimed_paths)
+            .into_iter()
+            .filter(|(_, path)| self.key(&self.root_path.join(paths::to_local(path))) != key)
+            .collect();
+    }
+}
+
+/// Part keys look like `/library/parts/<id>/<timestamp>/file.ext`, the timestamp
+/// changes without the file changing.
+fn part_id(part_key: &str) -> &str {
+    part_key
+        .strip_prefix(PARTS_PREFIX)
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or(part_key)
+}
+
+/// Whether both lists point to the same files, in any order.
+pub fn has_same_files(known: &[Item], current: &[Item]) -> bool {
+    let files = |tracks: &[Item]| {
+        let mut files: Vec<(String, Option<u64>)> = tracks
+            .iter()
+            .filter_map(|track| Some((part_id(&track.track_key()?).to_string(), track.size())))
+            .collect();
+        files.sort();
+        files
+    };
+    files(known) == files(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::m3u::TrackData;
+    use crate::template;
+    use crate::test_support::TempDir;
+
+    fn playlist(smart: bool, updated_at: Option<u64>) -> Playlist {
+        Playlist {
+            rating_key: String::from("1"),
+            title: String::from("Road Trip"),
+            smart,
+            playlist_type: String::from("audio"),
+            leaf_count: 4,
+            updated_at,
+        }
+    }
+
+    fn synced_manifest(updated_at: Option<u64>) -> Manifest {
+        let mut manifest = Manifest::default();
+        manifest.record_playlist(
+            "1",
+            PlaylistEntry {
+                title: String::from("Road Trip"),
+                file: std::env::temp_dir().to_string_lossy().to_string(),
+                updated_at,
+                settings: String::from("settings"),
+            },
+        );
+        manifest
+    }
+
+    fn track(part_key: &str, size: u64, updated_at: u64) -> Item {
+        Item::new(
+            String::from("Song.mp3"),
+            vec![
+                TrackData::Key(part_key.to_string()),
+                TrackData::Size(size),
+                TrackData::UpdatedAt(updated_at),
+            ],
+        )
+    }
+
+    fn manifest_with_file(part_key: &str, size: u64, updated_at: u64) -> Manifest {
+        let mut manifest = Manifest::default();
+        manifest.files.insert(
+            String::from("Song.mp3"),
+            FileEntry {
+                part_key: part_key.to_string(),
+                size: Some(size),
+                updated_at: Some(updated_at),
+                playlists: BTreeSet::from([String::from("1")]),
+            },
+        );
+        manifest
+    }
+
+    #[test]
+    fn skips_playlists_with_the_same_update_and_settings() {
+        let manifest = synced_manifest(Some(500));
+
+        assert!(
+            manifest
+                .is_unchanged(&playlist(false, Some(500)), "settings")
+                .is_some()
+        );
+        assert!(
+            manifest
+                .is_unchanged(&playlist(false, Some(501)), "settings")
+                .is_none()
+        );
+        assert!(
+            manifest
+                .is_unchanged(&playlist(false, Some(500)), "other settings")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn never_skips_smart_playlists() {
+        let manifest = synced_manifest(Some(500));
+
+        assert!(
+            manifest
+                .is_unchanged(&playlist(true, Some(500)), "settings")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn never_skips_playlists_without_update_time() {
+        let manifest = synced_manifest(None);
+
+        assert!(
+            manifest
+                .is_unchanged(&playlist(false, None), "settings")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn detects_upgrades_from_the_part() {
+        let manifest = manifest_with_file("/library/parts/1/file.mp3", 1000, 200);
+        let full_path = Path::new("Song.mp3");
+
+        assert!(!manifest.is_upgraded(&track("/library/parts/1/file.mp3", 1000, 200), full_path));
+        assert!(manifest.is_upgraded(&track("/library/parts/2/file.flac", 1000, 200), full_path));
+        assert!(manifest.is_upgraded(&track("/library/parts/1/file.mp3", 2000, 200), full_path));
+    }
+
+    #[test]
+    fn ignores_part_key_timestamps() {
+        let manifest = manifest_with_file("/library/parts/1/1586553226/file.mp3", 1000, 200);
+        let full_path = Path::new("Song.mp3");
+
+        assert!(!manifest.is_upgraded(
+            &track("/library/parts/1/1700000000/file.mp3", 1000, 200),
+            full_path
+        ));
+        assert!(manifest.is_upgraded(
+            &track("/library/parts/1/1700000000/file.mp3", 2000, 200),
+            full_path
+        ));
+    }
+
+    #[test]
+    fn notices_files_replaced_on_the_server() {
+        let known = vec![
+            track("/library/parts/1/1586553226/file.mp3", 1000, 200),
+            track("/library/parts/2/1586553226/file.mp3", 500, 200),
+        ];
+        let replaced = vec![
+            track("/library/parts/1/1700000000/file.mp3", 1200, 300),
+            track("/library/parts/2/1586553226/file.mp3", 500, 200),
+        ];
+        let reordered = vec![
+            track("/library/parts/2/1700000000/file.mp3", 500, 300),
+            track("/library/parts/1/1700000000/file.mp3", 1000, 300),
+        ];
+
+        assert!(!has_same_files(&known, &replaced));
+        assert!(has_same_files(&known, &reordered));
+    }
+
+    #[test]
+    fn ignores_metadata_updates() {
+        let manifest = manifest_with_file("/library/parts/1/file.mp3", 1000, 200);
+
+        assert!(!manifest.is_upgraded(
+            &track("/library/parts/1/file.mp3", 1000, 300),
+            Path::new("Song.mp3")
+        ));
+    }
+
+    fn templated(key: &str, path: &str) -> Vec<(String, Item)> {
+        vec![(key.to_string(), Item::new(path.to_string(), vec![]))]
+    }
+
+    fn manifest_claiming(root: &TempDir, path: &str) -> Manifest {
+        let mut manifest = Manifest::load(root.path()).unwrap();
+        let mut tracks = templated("1:0", path);
+        template::resolve_collisions(&mut tracks, &mut manifest.claimed_paths);
+        let track = track("/library/parts/1/file.mp3", 4, 200);
+        root.file(path, b"song");
+        manifest.record_tracks(
+            "1",
+            &[Item::new(path.to_string(), track.metadata)],
+            root.path(),
+            &HashSet::new(),
+        );
+        manifest
+    }
+
+    #[test]
+    fn keeps_paths_claimed_by_existing_files() {
+        let root = TempDir::new();
+        let mut manifest = manifest_claiming(&root, "Rush/Song.mp3");
+
+        let mut tracks = templated("2:0", "Rush/Song.mp3");
+        template::resolve_collisions(&mut tracks, &mut manifest.claimed_paths);
+
+        assert_eq!("Rush/Song (2).mp3", tracks[0].1.path);
+    }
+
+    #[test]
+    fn releases_the_paths_of_pruned_files() {
+        let root = TempDir::new();
+        let mut manifest = manifest_claiming(&root, "Rush/Song.mp3");
+        manifest.record_tracks("1", &[], root.path(), &HashSet::new());
+        fs::remove_file(root.path().join("Rush/Song.mp3")).unwrap();
+        manifest.forget(&root.path().join("Rush/Song.mp3"));
+
+        let mut tracks = templated("2:0", "Rush/Song.mp3");
+        template::resolve_collisions(&mut tracks, &mut manifest.claimed_paths);
+
+        assert_eq!("Rush/Song.mp3", tracks[0].1.path);
+        assert!(manifest.files.is_empty());
+    }
+
+    #[test]
+    fn releases_the_paths_of_removed_files() {
+        let root = TempDir::new();
+        let mut manifest = manifest_claiming(&root, "Rush/Song.mp3");
+        fs::remove_file(root.path().join("Rush/Song.mp3")).unwrap();
+        manifest.record_tracks("1", &[], root.path(), &HashSet::new());
+
+        let mut tracks = templated("2:0", "Rush/Song.mp3");
+        template::resolve_collisions(&mut tracks, &mut manifest.claimed_paths);
+
+        assert_eq!("Rush/Song.mp3", tracks[0].1.path);
+        assert!(manifest.files.is_empty());
+    }
+}
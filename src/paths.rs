@@ -0,0 +1,445 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TargetFs {
+    Fat32,
+    Exfat,
+    Ntfs,
+    Posix,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PathStyle {
+    Relative,
+    Absolute,
+    Windows,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathOptions {
+    pub normalization: Option<Normalization>,
+    pub target_fs: Option<TargetFs>,
+}
+
+const MAX_SEGMENT_LENGTH: usize = 255;
+const MAX_EXTENSION_LENGTH: usize = 10;
+const WINDOWS_INVALID_CHARACTERS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Normalization {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Normalization::Nfc => value.nfc().collect(),
+            Normalization::Nfd => value.nfd().collect(),
+        }
+    }
+}
+
+impl TargetFs {
+    fn is_windows(&self) -> bool {
+        !matches!(self, TargetFs::Posix)
+    }
+
+    fn length(&self, value: &str) -> usize {
+        if self.is_windows() {
+            value.encode_utf16().count()
+        } else {
+            value.len()
+        }
+    }
+
+    pub fn sanitize(&self, path: &str) -> String {
+        let mut sanitized = String::new();
+        let mut segment = String::new();
+        let mut first = true;
+        for c in path.chars() {
+            if c == '/' || c == '\\' {
+                sanitized.push_str(&self.sanitize_segment(&segment, first, false));
+                sanitized.push(c);
+                segment.clear();
+                first = false;
+            } else {
+                segment.push(c);
+            }
+        }
+        sanitized.push_str(&self.sanitize_segment(&segment, first, true));
+        sanitized
+    }
+
+    fn sanitize_segment(&self, segment: &str, first: bool, file_name: bool) -> String {
+        if segment.is_empty() || segment == "." || segment == ".." || (first && is_drive(segment)) {
+            return segment.to_string();
+        }
+        let mut sanitized: String = if self.is_windows() {
+            segment
+                .chars()
+                .map(|c| {
+                    if c.is_control() || WINDOWS_INVALID_CHARACTERS.contains(&c) {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
+                .collect()
+        } else {
+            segment.replace('\0', "_")
+        };
+
+        if self.is_windows() {
+            sanitized = sanitized.trim_end_matches(['.', ' ']).to_string();
+            let base_name = sanitized.split('.').next().unwrap_or_default();
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(base_name))
+            {
+                sanitized.insert(base_name.len(), '_');
+            }
+        }
+
+        let extension = match sanitized.rsplit_once('.') {
+            Some((stem, extension))
+                if file_name && !stem.is_empty() && extension.len() <= MAX_EXTENSION_LENGTH =>
+            {
+                format!(".{}", extension)
+            }
+            _ => String::new(),
+        };
+        let mut stem = sanitized[..sanitized.len() - extension.len()].to_string();
+        while !stem.is_empty() && self.length(&stem) + self.length(&extension) > MAX_SEGMENT_LENGTH
+        {
+            stem.pop();
+        }
+        sanitized = stem + &extension;
+
+        if self.is_windows() {
+            sanitized = sanitized.trim_end_matches(['.', ' ']).to_string();
+        }
+        if sanitized.is_empty() {
+            sanitized.push('_');
+        }
+        sanitized
+    }
+}
+
+fn is_drive(segment: &str) -> bool {
+    let mut chars = segment.chars();
+    matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(letter), Some(':'), None) if letter.is_ascii_alphabetic()
+    )
+}
+
+impl PathOptions {
+    pub fn apply(&self, path: &str) -> String {
+        let path = match self.normalization {
+            Some(normalization) => normalization.apply(path),
+            None => path.to_string(),
+        };
+        match self.target_fs {
+            Some(target_fs) => target_fs.sanitize(&path),
+            None => path,
+        }
+    }
+}
+
+impl PathStyle {
+    pub fn format(
+        &self,
+        path: &str,
+        track_root: &Path,
+        playlist_directory: &Path,
+        drive: Option<&str>,
+    ) -> String {
+        let full_path = track_root.join(to_local(path));
+        match (self, drive) {
+            (PathStyle::Relative, _) => relative_path(playlist_directory, &full_path)
+                .to_string_lossy()
+                .to_string(),
+            (PathStyle::Absolute, _) => std::path::absolute(&full_path)
+                .unwrap_or(full_path)
+                .to_string_lossy()
+                .to_string(),
+            (PathStyle::Windows, Some(drive)) => format!(
+                "{}\\{}",
+                drive.trim_end_matches(['/', '\\']),
+                windows_separators(&relative_path(track_root, &full_path))
+            ),
+            (PathStyle::Windows, None) => {
+                windows_separators(&relative_path(playlist_directory, &full_path))
+            }
+        }
+    }
+}
+
+fn windows_separators(path: &Path) -> String {
+    path.to_string_lossy().replace('/', "\\")
+}
+
+pub fn relative_path(from_directory: &Path, to: &Path) -> PathBuf {
+    let from = lexical_absolute(from_directory);
+    let to = lexical_absolute(to);
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in from.iter().skip(common) {
+        relative.push("..");
+    }
+    for component in to.iter().skip(common) {
+        relative.push(component);
+    }
+    relative
+}
+
+fn lexical_absolute(path: &Path) -> Vec<OsString> {
+    let mut components = vec![];
+    let current_directory = if path.is_absolute() {
+        None
+    } else {
+        std::env::current_dir().ok()
+    };
+    for component in current_directory
+        .iter()
+        .flat_map(|directory| directory.components())
+        .chain(path.components())
+    {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                components.pop();
+            }
+            component => components.push(component.as_os_str().to_os_string()),
+        }
+    }
+    components
+}
+
+pub fn to_local(path: &str) -> PathBuf {
+    if path.contains('/') || !path.contains('\\') || cfg!(windows) {
+        return PathBuf::from(path);
+    }
+    let path = path.replace('\\', "/");
+    match path.split_once(":/") {
+        Some((drive, rest)) if drive.len() == 1 => PathBuf::from(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+pub fn walk(root_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut directories = vec![root_path.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                directories.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+pub fn locate(path: &Path) -> Option<PathBuf> {
+    if path.exists() {
+        return Some(path.to_path_buf());
+    }
+    let mut located = PathBuf::new();
+    for component in path.components() {
+        let candidate = located.join(component);
+        if candidate.exists() {
+            located = candidate;
+            continue;
+        }
+        let Component::Normal(name) = component else {
+            return None;
+        };
+        let name: String = name.to_str()?.nfc().collect();
+        let directory = if located.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            located.as_path()
+        };
+        let entry = fs::read_dir(directory).ok()?.flatten().find(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|entry_name| entry_name.nfc().eq(name.chars()))
+        })?;
+        located.push(entry.file_name());
+    }
+    Some(located)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    const NFC_NAME: &str = "Beyonc\u{e9}";
+    const NFD_NAME: &str = "Beyonce\u{301}";
+
+    #[test]
+    fn locates_decomposed_names_from_composed_paths() {
+        let root = TempDir::new();
+        let on_disk = root.file(&format!("{}/Halo.m4a", NFD_NAME), b"song");
+
+        let located = locate(&root.path().join(NFC_NAME).join("Halo.m4a"));
+
+        assert_eq!(Some(on_disk), located);
+    }
+
+    #[test]
+    fn locates_composed_names_from_decomposed_paths() {
+        let root = TempDir::new();
+        let on_disk = root.file(&format!("Music/{}.m4a", NFC_NAME), b"song");
+
+        let located = locate(&root.path().join(format!("Music/{}.m4a", NFD_NAME)));
+
+        assert_eq!(Some(on_disk), located);
+    }
+
+    #[test]
+    fn does_not_locate_missing_files() {
+        let root = TempDir::new();
+        root.file(&format!("{}/Halo.m4a", NFD_NAME), b"song");
+
+        assert_eq!(None, locate(&root.path().join(NFC_NAME).join("Crazy.m4a")));
+    }
+
+    #[test]
+    fn replaces_invalid_characters_per_target_fs() {
+        assert_eq!(
+            "AC_DC/Who Made Who_/T.N.T..mp3",
+            TargetFs::Fat32.sanitize("AC:DC/Who Made Who?/T.N.T..mp3")
+        );
+        assert_eq!(
+            "AC:DC/Who Made Who?/T.N.T..mp3",
+            TargetFs::Posix.sanitize("AC:DC/Who Made Who?/T.N.T..mp3")
+        );
+        assert_eq!(
+            "C:\\Music\\a_b.mp3",
+            TargetFs::Ntfs.sanitize("C:\\Music\\a|b.mp3")
+        );
+    }
+
+    #[test]
+    fn truncates_long_names_keeping_their_extension() {
+        let sanitized = TargetFs::Posix.sanitize(&format!("{}.flac", "a".repeat(300)));
+
+        assert_eq!(MAX_SEGMENT_LENGTH, sanitized.len());
+        assert!(sanitized.ends_with("a.flac"));
+    }
+
+    #[test]
+    fn truncates_reserved_names_after_suffixing_them() {
+        let segment = format!("CON.{}", "a".repeat(MAX_SEGMENT_LENGTH - 4));
+        let sanitized = TargetFs::Ntfs.sanitize(&format!("Artist/{}", segment));
+        let file_name = sanitized.strip_prefix("Artist/").unwrap();
+
+        assert!(file_name.starts_with("CON_."));
+        assert_eq!(MAX_SEGMENT_LENGTH, TargetFs::Ntfs.length(file_name));
+    }
+
+    #[test]
+    fn suffixes_reserved_names_with_trailing_dots() {
+        assert_eq!("CON_", TargetFs::Fat32.sanitize("CON."));
+        assert_eq!("aux_.mp3", TargetFs::Exfat.sanitize("aux.mp3"));
+    }
+
+    #[test]
+    fn walks_up_to_reach_sibling_directories() {
+        assert_eq!(
+            PathBuf::from("../../Rush/a.m4a"),
+            relative_path(
+                Path::new("/media/car/playlists/rock"),
+                Path::new("/media/car/Rush/a.m4a")
+            )
+        );
+        assert_eq!(
+            PathBuf::from("Rush/a.m4a"),
+            relative_path(
+                Path::new("/media/car/./x/.."),
+                Path::new("/media/car/Rush/a.m4a")
+            )
+        );
+    }
+
+    #[test]
+    fn walks_up_to_the_root_between_different_roots() {
+        assert_eq!(
+            PathBuf::from("../../music/a.m4a"),
+            relative_path(Path::new("/media/car"), Path::new("/music/a.m4a"))
+        );
+    }
+
+    #[test]
+    fn formats_relative_and_absolute_paths() {
+        let track_root = Path::new("/media/car");
+        let playlist_directory = Path::new("/media/car/playlists");
+
+        assert_eq!(
+            "../Rush/a.m4a",
+            PathStyle::Relative.format("Rush/a.m4a", track_root, playlist_directory, None)
+        );
+        assert_eq!(
+            "/media/car/Rush/a.m4a",
+            PathStyle::Absolute.format("Rush/a.m4a", track_root, playlist_directory, None)
+        );
+    }
+
+    #[test]
+    fn formats_windows_paths() {
+        let track_root = Path::new("/media/car");
+        let playlist_directory = Path::new("/media/car/playlists");
+
+        assert_eq!(
+            "..\\Rush\\Power Windows\\a.m4a",
+            PathStyle::Windows.format(
+                "Rush/Power Windows/a.m4a",
+                track_root,
+                playlist_directory,
+                None
+            )
+        );
+        assert_eq!(
+            "E:\\Rush\\a.m4a",
+            PathStyle::Windows.format("Rush/a.m4a", track_root, playlist_directory, Some("E:\\"))
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn converts_windows_paths_to_local_paths() {
+        assert_eq!(
+            PathBuf::from("Music/Rush/a.m4a"),
+            to_local("Music\\Rush\\a.m4a")
+        );
+        assert_eq!(
+            PathBuf::from("Music/Rush/a.m4a"),
+            to_local("C:\\Music\\Rush\\a.m4a")
+        );
+        assert_eq!(
+            PathBuf::from("Rush/AC\\DC.m4a"),
+            to_local("Rush/AC\\DC.m4a")
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+use quick_xml::DeError;
+use reqwest::StatusCode;
+
+#[derive(Debug)]
+pub enum PlexError {
+    InvalidUrl(String),
+    Connection(reqwest::Error),
+    Status(StatusCode),
+    Unauthorized,
+    NotFound(String),
+    UnexpectedContent(String),
+    PinExpired,
+    Decode(DeError),
+    DecodeJson(serde_json::Error),
+}
+
+impl fmt::Display for PlexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlexError::InvalidUrl(url) => write!(f, "invalid url {}", url),
+            PlexError::Connection(error) => write!(f, "could not reach server: {}", error),
+            PlexError::Status(status) => write!(f, "server responded with {}", status),
+            PlexError::Unauthorized => write!(
+                f,
+                "server responded with {}, check your token",
+                StatusCode::UNAUTHORIZED
+            ),
+            PlexError::NotFound(path) => write!(
+                f,
+                "server responded with {} for {}",
+                StatusCode::NOT_FOUND,
+                path
+            ),
+            PlexError::PinExpired => write!(f, "login code expired before being linked"),
+            PlexError::UnexpectedContent(content_type) => {
+                write!(f, "server sent {} instead of media", content_type)
+            }
+            PlexError::Decode(error) => write!(f, "could not decode response: {}", error),
+            PlexError::DecodeJson(error) => write!(f, "could not decode response: {}", error),
+        }
+    }
+}
+
+impl PlexError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            PlexError::Status(status) => Some(*status),
+            PlexError::Unauthorized => Some(StatusCode::UNAUTHORIZED),
+            PlexError::NotFound(_) => Some(StatusCode::NOT_FOUND),
+            PlexError::Connection(error) => error.status(),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for PlexError {
+    fn from(error: reqwest::Error) -> Self {
+        PlexError::Connection(error)
+    }
+}
+
+impl From<DeError> for PlexError {
+    fn from(error: DeError) -> Self {
+        PlexError::Decode(error)
+    }
+}
+
+impl From<serde_json::Error> for PlexError {
+    fn from(error: serde_json::Error) -> Self {
+        PlexError::DecodeJson(error)
+    }
+}
@@ -0,0 +1,152 @@
+use std::io;
+use std::net::{IpAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+pub const GDM_ADDRESS: &str = "255.255.255.255";
+pub const GDM_PORT: u16 = 32414;
+const SEARCH_MESSAGE: &[u8] = b"M-SEARCH * HTTP/1.1\r\n\r\n";
+const SERVER_CONTENT_TYPE: &str = "plex/media-server";
+const DEFAULT_SERVER_PORT: u16 = 32400;
+
+#[derive(Debug, Clone)]
+pub struct GdmServer {
+    pub name: String,
+    pub client_identifier: String,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl GdmServer {
+    pub fn uri(&self) -> String {
+        match self.address {
+            IpAddr::V6(address) => format!("http://[{}]:{}", address, self.port),
+            IpAddr::V4(address) => format!("http://{}:{}", address, self.port),
+        }
+    }
+
+    fn parse(address: IpAddr, message: &str) -> Option<GdmServer> {
+        let mut content_type = None;
+        let mut name = None;
+        let mut client_identifier = None;
+        let mut port = DEFAULT_SERVER_PORT;
+        for line in message.lines().skip(1) {
+            let mut splitted = line.splitn(2, ':');
+            match (
+                splitted.next().map(str::trim),
+                splitted.next().map(str::trim),
+            ) {
+                (Some("Content-Type"), Some(value)) => content_type = Some(value.to_string()),
+                (Some("Name"), Some(value)) => name = Some(value.to_string()),
+                (Some("Resource-Identifier"), Some(value)) => {
+                    client_identifier = Some(value.to_string())
+                }
+                (Some("Port"), Some(value)) => port = value.parse().unwrap_or(DEFAULT_SERVER_PORT),
+                _ => (),
+            }
+        }
+        match (content_type, name, client_identifier) {
+            (Some(content_type), Some(name), Some(client_identifier))
+                if content_type == SERVER_CONTENT_TYPE =>
+            {
+                Some(GdmServer {
+                    name,
+                    client_identifier,
+                    address,
+                    port,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn discover(address: &str, port: u16, timeout: Duration) -> io::Result<Vec<GdmServer>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    info!("GDM M-SEARCH to {}:{}", address, port);
+    socket.send_to(SEARCH_MESSAGE, (address, port))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut servers: Vec<GdmServer> = vec![];
+    let mut buffer = [0; 2048];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buffer) {
+            Ok((length, source)) => {
+                let message = String::from_utf8_lossy(&buffer[..length]);
+                match GdmServer::parse(source.ip(), &message) {
+                    Some(server)
+                        if !servers
+                            .iter()
+                            .any(|known| known.client_identifier == server.client_identifier) =>
+                    {
+                        servers.push(server)
+                    }
+                    _ => (),
+                }
+            }
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                break;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+
+    #[test]
+    fn parses_server_responses() {
+        let message = "HTTP/1.0 200 OK\r\nContent-Type: plex/media-server\r\nName: Home\r\nPort: 32410\r\nResource-Identifier: abc123\r\n\r\n";
+
+        let server = GdmServer::parse(ADDRESS, message).unwrap();
+
+        assert_eq!("Home", server.name);
+        assert_eq!("abc123", server.client_identifier);
+        assert_eq!("http://192.168.1.10:32410", server.uri());
+    }
+
+    #[test]
+    fn defaults_to_the_server_port() {
+        let message = "HTTP/1.0 200 OK\r\nContent-Type: plex/media-server\r\nName: Home\r\nPort: unknown\r\nResource-Identifier: abc123\r\n";
+
+        let server = GdmServer::parse(ADDRESS, message).unwrap();
+
+        assert_eq!(DEFAULT_SERVER_PORT, server.port);
+    }
+
+    #[test]
+    fn ignores_other_players_and_incomplete_responses() {
+        let player = "HTTP/1.0 200 OK\r\nContent-Type: plex/media-player\r\nName: TV\r\nResource-Identifier: tv\r\n";
+        let anonymous =
+            "HTTP/1.0 200 OK\r\nContent-Type: plex/media-server\r\nResource-Identifier: abc123\r\n";
+
+        assert!(GdmServer::parse(ADDRESS, player).is_none());
+        assert!(GdmServer::parse(ADDRESS, anonymous).is_none());
+    }
+
+    #[test]
+    fn formats_ipv6_uris() {
+        let message = "HTTP/1.0 200 OK\r\nContent-Type: plex/media-server\r\nName: Home\r\nResource-Identifier: abc123\r\n";
+
+        let server = GdmServer::parse("fe80::1".parse().unwrap(), message).unwrap();
+
+        assert_eq!("http://[fe80::1]:32400", server.uri());
+    }
+}
@@ -1,6 +1,12 @@
 mod deserializer;
+pub mod error;
+pub mod gdm;
 pub mod playlist;
+#[allow(clippy::module_inception)]
 pub mod plex_client;
+pub mod plex_tv;
+pub mod server;
 pub mod track;
 
+pub use error::PlexError;
 pub use plex_client::PlexClient;
@@ -1,5 +1,7 @@
 use crate::plex_client::deserializer::deserialize_integer_bool;
+use regex::Regex;
 use serde::Deserialize;
+use std::fmt;
 
 #[derive(Debug, Deserialize)]
 pub struct MediaContainer {
@@ -26,28 +28,109 @@ pub struct Playlist {
 
     #[serde(rename = "@leafCount")]
     pub leaf_count: u32,
+
+    #[serde(rename = "@updatedAt")]
+    pub updated_at: Option<u64>,
 }
 
 pub struct PlaylistFilter {
     pub only_playlist_type: Option<String>,
+    pub smart: Option<bool>,
+    pub title_glob: Option<String>,
+    pub excludes: Vec<String>,
 }
 
-impl Playlist {
-    pub fn to_string(&self) -> String {
-        let mut output = format!(
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
             "{}: {} [{}]",
             self.rating_key, self.title, self.playlist_type
-        );
+        )?;
         if self.smart {
-            output = format!("{} [Smart]", output)
+            write!(f, " [Smart]")?;
         }
-        format!("{} [{} tracks]", output, self.leaf_count)
+        write!(f, " [{} tracks]", self.leaf_count)
     }
+}
 
+impl Playlist {
     pub fn matches(&self, filter: &PlaylistFilter) -> bool {
-        match &filter.only_playlist_type {
+        let type_matches = match &filter.only_playlist_type {
             None => true,
             Some(only_filter) => *only_filter == self.playlist_type,
-        }
+        };
+        let smart_matches = match filter.smart {
+            None => true,
+            Some(smart) => smart == self.smart,
+        };
+        let title_matches = match &filter.title_glob {
+            None => true,
+            Some(title_glob) => glob_matches(title_glob, &self.title),
+        };
+        type_matches && smart_matches && title_matches && !self.is_excluded(filter)
+    }
+
+    pub fn is_excluded(&self, filter: &PlaylistFilter) -> bool {
+        filter
+            .excludes
+            .iter()
+            .any(|exclude| *exclude == self.rating_key || glob_matches(exclude, &self.title))
+    }
+}
+
+fn glob_matches(glob: &str, title: &str) -> bool {
+    let pattern = glob
+        .split('*')
+        .map(|part| {
+            part.split('?')
+                .map(regex::escape)
+                .collect::<Vec<String>>()
+                .join(".")
+        })
+        .collect::<Vec<String>>()
+        .join(".*");
+    Regex::new(&format!("(?i)^{}$", pattern)).is_ok_and(|regex| regex.is_match(title))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_any_characters_with_a_star() {
+        assert!(glob_matches("Road*", "Road Trip"));
+        assert!(glob_matches("*Trip", "Road Trip"));
+        assert!(glob_matches("*", "Road Trip"));
+        assert!(glob_matches("Road*", "Road"));
+        assert!(!glob_matches("Road*", "Off Road"));
+    }
+
+    #[test]
+    fn matches_one_character_with_a_question_mark() {
+        assert!(glob_matches("Mix 200?", "Mix 2009"));
+        assert!(!glob_matches("Mix 200?", "Mix 200"));
+        assert!(!glob_matches("Mix 200?", "Mix 20091"));
+    }
+
+    #[test]
+    fn matches_case_insensitively() {
+        assert!(glob_matches("recently*", "Recently Added"));
+        assert!(glob_matches("ROAD TRIP", "Road Trip"));
+    }
+
+    #[test]
+    fn matches_regex_characters_literally() {
+        assert!(glob_matches("Hits (2020)", "Hits (2020)"));
+        assert!(glob_matches("C++ [Live]*", "C++ [Live] Session"));
+        assert!(glob_matches("$1.99 ^_^", "$1.99 ^_^"));
+        assert!(!glob_matches("Hits.", "Hits!"));
+        assert!(!glob_matches("A|B", "A"));
+    }
+
+    #[test]
+    fn matches_the_whole_title() {
+        assert!(!glob_matches("Road", "Road Trip"));
+        assert!(!glob_matches("Trip", "Road Trip"));
     }
 }
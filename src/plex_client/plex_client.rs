@@ -1,73 +1,350 @@
+use std::collections::HashSet;
+
 use quick_xml::de::from_str;
-use reqwest::blocking::Response;
+use reqwest::StatusCode;
+use reqwest::Url;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{CONTENT_TYPE, RANGE};
 
+use crate::logger;
+use crate::plex_client::PlexError;
 use crate::plex_client::playlist::MediaContainer as PlaylistMediaContainer;
 use crate::plex_client::track::MediaContainer as TrackMediaContainer;
+use crate::plex_client::track::{SearchMediaContainer, Track};
 use log::info;
 
+pub const TOKEN_HEADER: &str = "X-Plex-Token";
+const CONTAINER_START_HEADER: &str = "X-Plex-Container-Start";
+const CONTAINER_SIZE_HEADER: &str = "X-Plex-Container-Size";
+const TRACK_TYPE: &str = "10";
+
 #[derive(Clone)]
 pub struct PlexClient {
     pub server: String,
     token: Option<String>,
+    http: Client,
 }
 
 impl PlexClient {
     pub fn new(server: String, token: Option<String>) -> PlexClient {
+        if let Some(token) = &token {
+            logger::add_secret(token);
+        }
         PlexClient {
-            server: server,
-            token: token,
+            server,
+            token,
+            http: Client::new(),
         }
     }
-    pub fn list_playlists(&self) -> PlaylistMediaContainer {
-        let body = self.get_as_text("/playlists".to_string());
+    pub fn list_playlists(&self) -> Result<PlaylistMediaContainer, PlexError> {
+        let body = self.get_as_text("/playlists", &[])?;
+
+        Ok(from_str::<PlaylistMediaContainer>(&body)?)
+    }
+
+    pub fn get_playlist_page(
+        &self,
+        rating_key: &str,
+        start: u32,
+        size: u32,
+    ) -> Result<TrackMediaContainer, PlexError> {
+        let path = format!("/playlists/{}/items", rating_key);
+        let response = self
+            .request(&path, &[])?
+            .header(CONTAINER_START_HEADER, start)
+            .header(CONTAINER_SIZE_HEADER, size)
+            .send()?;
+        let body = check_status(response, path)?.text()?;
+
+        info!("Fetched {} bytes from item {}", body.len(), start);
+
+        Ok(from_str::<TrackMediaContainer>(&body)?)
+    }
 
-        match from_str::<PlaylistMediaContainer>(&body) {
-            Ok(media_container) => media_container,
-            Err(error) => panic!("{:#?}", error),
+    pub fn playlist_pages(&self, rating_key: String, page_size: u32) -> PlaylistPages<'_> {
+        PlaylistPages {
+            plex_client: self,
+            rating_key,
+            page_size: page_size.max(1),
+            start: 0,
+            seen: HashSet::new(),
+            done: false,
         }
     }
 
-    pub fn get_playlist(&self, rating_key: String) -> TrackMediaContainer {
-        let body = self.get_as_text(format!("/playlists/{}/items", rating_key).to_string());
+    pub fn search_tracks(&self, query: &str) -> Result<Vec<Track>, PlexError> {
+        let body = self.get_as_text(
+            "/search",
+            &[
+                ("type", TRACK_TYPE.to_string()),
+                ("query", query.to_string()),
+            ],
+        )?;
 
-        info!("{:?}", body);
+        Ok(from_str::<SearchMediaContainer>(&body)?.tracks)
+    }
 
-        match from_str::<TrackMediaContainer>(&body) {
-            Ok(media_container) => media_container,
-            Err(error) => panic!("{:#?}", error),
+    pub fn get_part(&self, part_key: String, offset: u64) -> Result<Response, PlexError> {
+        let mut request = self.request(&part_key, &[])?;
+        if offset > 0 {
+            info!("Resuming {} from byte {}", part_key, offset);
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send()?;
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+            return Ok(response);
+        }
+        let response = check_status(response, part_key)?;
+        match response.headers().get(CONTENT_TYPE) {
+            Some(content_type) if !is_media_type(content_type.to_str().unwrap_or_default()) => Err(
+                PlexError::UnexpectedContent(content_type.to_str().unwrap_or_default().to_string()),
+            ),
+            _ => Ok(response),
         }
     }
 
-    pub fn get_part(&self, part_key: String) -> Response {
-        self.get_response(part_key)
+    fn get_as_text(&self, path: &str, query: &[(&str, String)]) -> Result<String, PlexError> {
+        let response = self.call(path, query)?;
+        Ok(response.text()?)
     }
 
-    fn get_response(&self, path: String) -> Response {
-        self.call(path)
+    fn call(&self, path: &str, query: &[(&str, String)]) -> Result<Response, PlexError> {
+        let response = self.request(path, query)?.send()?;
+        check_status(response, path.to_string())
     }
 
-    fn get_as_text(&self, path: String) -> String {
-        let response = self.call(path);
-        match response.text() {
-            Ok(body) => body,
-            Err(error) => panic!("{:#?}", error),
+    fn request(&self, path: &str, query: &[(&str, String)]) -> Result<RequestBuilder, PlexError> {
+        let client_url = self.client_url(path, query)?;
+        info!("GET {}", client_url);
+        let mut request = self.http.get(client_url);
+        if let Some(token) = &self.token {
+            request = request.header(TOKEN_HEADER, token);
         }
+        Ok(request)
     }
 
-    fn call(&self, path: String) -> Response {
-        let client_url = self.client_url(path);
-        info!("GET {}", client_url);
-        match reqwest::blocking::get(client_url) {
-            Ok(response) => response,
-            Err(error) => panic!("{:#?}", error),
+    fn client_url(&self, path: &str, query: &[(&str, String)]) -> Result<Url, PlexError> {
+        let raw_url = format!("{}{}", self.server.trim_end_matches('/'), path);
+        let mut url = Url::parse(&raw_url).map_err(|_| PlexError::InvalidUrl(raw_url))?;
+        if !query.is_empty() {
+            url.query_pairs_mut()
+                .extend_pairs(query.iter().map(|(key, value)| (*key, value.as_str())));
         }
+        Ok(url)
     }
+}
 
-    fn client_url(&self, path: String) -> String {
-        let mut url = format!("{}{}", self.server, path);
-        if let Some(token) = &self.token {
-            url = format!("{}?X-Plex-Token={}", url, token);
+pub struct PlaylistPages<'a> {
+    plex_client: &'a PlexClient,
+    rating_key: String,
+    page_size: u32,
+    start: u32,
+    seen: HashSet<u32>,
+    done: bool,
+}
+
+impl Iterator for PlaylistPages<'_> {
+    type Item = Result<TrackMediaContainer, PlexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = self
+            .plex_client
+            .get_playlist_page(&self.rating_key, self.start, self.page_size);
+        match &page {
+            Ok(container) => {
+                let new_items = container
+                    .rating_keys()
+                    .filter(|key| self.seen.insert(*key))
+                    .count();
+                if container.item_count() > 0 && new_items == 0 {
+                    info!("Page at {} has no new items, stopping", self.start);
+                    self.done = true;
+                    return None;
+                }
+                let count = container.fetched_count();
+                self.start += count;
+                self.done = is_last_page(self.start, count, self.page_size, container.total_size);
+            }
+            Err(_) => self.done = true,
         }
-        url
+        Some(page)
+    }
+}
+
+fn is_last_page(start: u32, count: u32, page_size: u32, total_size: Option<u32>) -> bool {
+    match total_size {
+        _ if count == 0 || count > page_size => true,
+        Some(total_size) => start >= total_size,
+        None => count < page_size,
+    }
+}
+
+pub fn check_status(response: Response, path: String) -> Result<Response, PlexError> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err(PlexError::Unauthorized),
+        StatusCode::NOT_FOUND => Err(PlexError::NotFound(path)),
+        status => Err(PlexError::Status(status)),
+    }
+}
+
+fn is_media_type(content_type: &str) -> bool {
+    let content_type = content_type.to_lowercase();
+    !(content_type.starts_with("text/")
+        || content_type.contains("xml")
+        || content_type.contains("json")
+        || content_type.contains("html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{Response, TestServer};
+
+    #[test]
+    fn reports_error_statuses() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/playlists/1/items" => Response::new(401, "text/html", ""),
+            "/playlists/2/items" => Response::new(500, "text/html", ""),
+            "/playlists/3/items" => Response::new(200, "text/xml", "<html>"),
+            _ => Response::new(404, "text/html", ""),
+        });
+        let plex_client = PlexClient::new(server.url.clone(), None);
+
+        let get = |rating_key: &str| plex_client.get_playlist_page(rating_key, 0, 50);
+
+        assert!(matches!(get("1"), Err(PlexError::Unauthorized)));
+        assert!(matches!(
+            get("2"),
+            Err(PlexError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+        ));
+        assert!(matches!(get("3"), Err(PlexError::Decode(_))));
+        assert!(matches!(get("4"), Err(PlexError::NotFound(path)) if path == "/playlists/4/items"));
+    }
+
+    #[test]
+    fn stops_paging_at_total_size() {
+        assert!(!is_last_page(50, 50, 50, Some(120)));
+        assert!(!is_last_page(100, 45, 50, Some(120)));
+        assert!(is_last_page(120, 20, 50, Some(120)));
+    }
+
+    #[test]
+    fn stops_paging_on_short_page_without_total_size() {
+        assert!(!is_last_page(50, 50, 50, None));
+        assert!(is_last_page(70, 20, 50, None));
+    }
+
+    #[test]
+    fn stops_paging_on_empty_page() {
+        assert!(is_last_page(100, 0, 50, Some(120)));
+    }
+
+    #[test]
+    fn stops_paging_when_the_server_ignores_the_page_size() {
+        assert!(is_last_page(120, 120, 50, None));
+    }
+
+    fn page(rating_keys: &[u32]) -> String {
+        let tracks: String = rating_keys
+            .iter()
+            .map(|key| {
+                format!(
+                    r#"<Track ratingKey="{key}" title="Song {key}"><Media id="{key}"><Part id="{key}" key="/library/parts/{key}/file.m4a" file="/music/{key}.m4a"/></Media></Track>"#
+                )
+            })
+            .collect();
+        format!(
+            r#"<MediaContainer ratingKey="1" title="Mix">{}</MediaContainer>"#,
+            tracks
+        )
+    }
+
+    fn fetched_keys(server: &TestServer, page_size: u32) -> Vec<u32> {
+        let plex_client = PlexClient::new(server.url.clone(), None);
+        plex_client
+            .playlist_pages(String::from("1"), page_size)
+            .flat_map(|page| page.unwrap().tracks)
+            .map(|track| track.rating_key)
+            .collect()
+    }
+
+    #[test]
+    fn stops_paging_when_an_offset_returns_the_same_items() {
+        let server = TestServer::start(|_| Response::new(200, "text/xml", page(&[11, 12])));
+
+        assert_eq!(vec![11, 12], fetched_keys(&server, 2));
+        assert_eq!(2, server.requests().len());
+    }
+
+    #[test]
+    fn stops_paging_when_the_server_returns_everything() {
+        let server = TestServer::start(|_| Response::new(200, "text/xml", page(&[11, 12, 13])));
+
+        assert_eq!(vec![11, 12, 13], fetched_keys(&server, 2));
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn fetches_every_page() {
+        let server = TestServer::start(|request| {
+            let keys: &[u32] = match request.header(CONTAINER_START_HEADER) {
+                Some("0") => &[11, 12],
+                Some("2") => &[13, 14],
+                _ => &[15],
+            };
+            Response::new(200, "text/xml", page(keys))
+        });
+
+        assert_eq!(vec![11, 12, 13, 14, 15], fetched_keys(&server, 2));
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[test]
+    fn counts_items_that_are_not_tracks_or_videos() {
+        let container: TrackMediaContainer = from_str(
+            r#"<MediaContainer size="3" totalSize="10" ratingKey="1" title="Mixed">
+                <Track ratingKey="11" title="Song"><Media id="1"><Part id="1" key="/library/parts/1/file.m4a" file="/music/song.m4a"/></Media></Track>
+                <Photo ratingKey="12" title="Cover"/>
+                <Episode ratingKey="13" title="Clip"/>
+            </MediaContainer>"#,
+        )
+        .unwrap();
+        assert_eq!(container.item_count(), 1);
+        assert_eq!(container.fetched_count(), 3);
+    }
+
+    #[test]
+    fn keeps_the_query_of_part_keys() {
+        let plex_client = PlexClient::new(String::from("http://plex:32400/"), None);
+
+        let url = plex_client
+            .client_url(
+                "/library/parts/1/file.m4a?download=1&x=a%26b",
+                &[("type", String::from("10"))],
+            )
+            .unwrap();
+
+        assert_eq!(
+            "http://plex:32400/library/parts/1/file.m4a?download=1&x=a%26b&type=10",
+            url.as_str()
+        );
+    }
+
+    #[test]
+    fn sends_the_token_as_a_header() {
+        let server = TestServer::start(|_| Response::new(200, "audio/mpeg", "content"));
+        let plex_client = PlexClient::new(server.url.clone(), Some(String::from("HEADERTOKEN")));
+
+        plex_client
+            .get_part(String::from("/library/parts/1/file.m4a?download=1"), 0)
+            .unwrap();
+
+        let request = &server.requests()[0];
+        assert_eq!("/library/parts/1/file.m4a?download=1", request.path);
+        assert_eq!(Some("HEADERTOKEN"), request.header(TOKEN_HEADER));
     }
 }
@@ -0,0 +1,251 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::ACCEPT;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::logger;
+use crate::plex_client::PlexError;
+use crate::plex_client::plex_client::{TOKEN_HEADER, check_status};
+use log::{info, warn};
+
+pub const PLEX_TV_URL: &str = "https://plex.tv";
+const LINK_PATH: &str = "/link";
+const PRODUCT: &str = "plexm3u";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pin {
+    pub id: u64,
+    pub code: String,
+    pub auth_token: Option<String>,
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Resource {
+    pub name: String,
+    pub client_identifier: String,
+    #[serde(default)]
+    pub provides: String,
+    #[serde(default)]
+    pub owned: bool,
+    pub access_token: Option<String>,
+    #[serde(default)]
+    pub connections: Vec<ResourceConnection>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResourceConnection {
+    pub uri: String,
+    #[serde(default)]
+    pub local: bool,
+    #[serde(default)]
+    pub relay: bool,
+}
+
+#[derive(Clone)]
+pub struct PlexTvClient {
+    base_url: String,
+    client_identifier: String,
+    token: Option<String>,
+    http: Client,
+}
+
+impl PlexTvClient {
+    pub fn new(base_url: String, client_identifier: String, token: Option<String>) -> PlexTvClient {
+        if let Some(token) = &token {
+            logger::add_secret(token);
+        }
+        PlexTvClient {
+            base_url,
+            client_identifier,
+            token,
+            http: Client::new(),
+        }
+    }
+
+    pub fn create_pin(&self) -> Result<Pin, PlexError> {
+        self.send_json(self.http.post(self.url("/api/v2/pins?strong=false")?))
+    }
+
+    pub fn check_pin(&self, id: u64) -> Result<Pin, PlexError> {
+        let pin: Pin = self.send_json(self.http.get(self.url(&format!("/api/v2/pins/{}", id))?))?;
+        if let Some(token) = &pin.auth_token {
+            logger::add_secret(token);
+        }
+        Ok(pin)
+    }
+
+    pub fn wait_for_token(
+        &self,
+        pin: &Pin,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<String, PlexError> {
+        let timeout = pin.expires_in.map_or(timeout, |expires_in| {
+            timeout.min(Duration::from_secs(expires_in))
+        });
+        let started_at = Instant::now();
+        while started_at.elapsed() < timeout {
+            thread::sleep(poll_interval);
+            match self.check_pin(pin.id) {
+                Ok(Pin {
+                    auth_token: Some(token),
+                    ..
+                }) => return Ok(token),
+                Ok(_) => {}
+                Err(PlexError::NotFound(_)) => return Err(PlexError::PinExpired),
+                Err(error) => warn!("Could not check login code, retrying: {}", error),
+            }
+        }
+        Err(PlexError::PinExpired)
+    }
+
+    pub fn link_url(&self) -> String {
+        format!("{}{}", self.base_url.trim_end_matches('/'), LINK_PATH)
+    }
+
+    pub fn resources(&self) -> Result<Vec<Resource>, PlexError> {
+        let resources: Vec<Resource> = self.send_json(
+            self.http
+                .get(self.url("/api/v2/resources?includeHttps=1&includeRelay=1")?),
+        )?;
+        for resource in resources.iter() {
+            if let Some(token) = &resource.access_token {
+                logger::add_secret(token);
+            }
+        }
+        Ok(resources
+            .into_iter()
+            .filter(|resource| resource.provides.split(',').any(|kind| kind == "server"))
+            .collect())
+    }
+
+    fn send_json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, PlexError> {
+        let mut request = request
+            .header(ACCEPT, "application/json")
+            .header("X-Plex-Product", PRODUCT)
+            .header("X-Plex-Version", env!("CARGO_PKG_VERSION"))
+            .header("X-Plex-Client-Identifier", &self.client_identifier);
+        if let Some(token) = &self.token {
+            request = request.header(TOKEN_HEADER, token);
+        }
+        let response = request.send()?;
+        let path = response.url().path().to_string();
+        let body = check_status(response, path)?.text()?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    fn url(&self, path: &str) -> Result<Url, PlexError> {
+        let raw_url = format!("{}{}", self.base_url.trim_end_matches('/'), path);
+        let url = Url::parse(&raw_url).map_err(|_| PlexError::InvalidUrl(raw_url))?;
+        info!("{}", url);
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_support::{Response, TestServer};
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    fn client(server: &TestServer) -> PlexTvClient {
+        PlexTvClient::new(server.url.clone(), String::from("client"), None)
+    }
+
+    fn pin(expires_in: Option<u64>) -> Pin {
+        Pin {
+            id: 7,
+            code: String::from("ABCD"),
+            auth_token: None,
+            expires_in,
+        }
+    }
+
+    fn json(body: &str) -> Response {
+        Response::new(200, "application/json", body)
+    }
+
+    #[test]
+    fn links_to_the_overridden_url() {
+        let plex_tv_client =
+            PlexTvClient::new(String::from("http://localhost:32401/"), String::new(), None);
+
+        assert_eq!("http://localhost:32401/link", plex_tv_client.link_url());
+    }
+
+    #[test]
+    fn creates_a_pin() {
+        let server = TestServer::start(|_| json(r#"{"id": 7, "code": "ABCD", "expiresIn": 900}"#));
+
+        let pin = client(&server).create_pin().unwrap();
+
+        assert_eq!(
+            (7, "ABCD", Some(900)),
+            (pin.id, pin.code.as_str(), pin.expires_in)
+        );
+        let request = &server.requests()[0];
+        assert_eq!("/api/v2/pins?strong=false", request.path);
+        assert_eq!(Some("client"), request.header("X-Plex-Client-Identifier"));
+    }
+
+    #[test]
+    fn polls_until_the_pin_is_linked() {
+        let polls = AtomicUsize::new(0);
+        let server = TestServer::start(move |_| match polls.fetch_add(1, Ordering::SeqCst) {
+            0 => json(r#"{"id": 7, "code": "ABCD"}"#),
+            1 => Response::new(503, "text/plain", "unavailable"),
+            _ => json(r#"{"id": 7, "code": "ABCD", "authToken": "PINTOKEN"}"#),
+        });
+
+        let token =
+            client(&server).wait_for_token(&pin(None), Duration::from_secs(5), POLL_INTERVAL);
+
+        assert_eq!("PINTOKEN", token.unwrap());
+        assert_eq!(3, server.requests().len());
+        assert_eq!("/api/v2/pins/7", server.requests()[0].path);
+    }
+
+    #[test]
+    fn stops_when_the_pin_is_gone() {
+        let server = TestServer::start(|_| Response::new(404, "text/plain", "gone"));
+
+        let token =
+            client(&server).wait_for_token(&pin(None), Duration::from_secs(5), POLL_INTERVAL);
+
+        assert!(matches!(token, Err(PlexError::PinExpired)));
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn times_out_when_the_pin_is_never_linked() {
+        let server = TestServer::start(|_| json(r#"{"id": 7, "code": "ABCD"}"#));
+
+        let started_at = Instant::now();
+        let token =
+            client(&server).wait_for_token(&pin(None), Duration::from_millis(100), POLL_INTERVAL);
+
+        assert!(matches!(token, Err(PlexError::PinExpired)));
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn times_out_when_the_pin_expires() {
+        let server = TestServer::start(|_| json(r#"{"id": 7, "code": "ABCD"}"#));
+
+        let token =
+            client(&server).wait_for_token(&pin(Some(0)), Duration::from_secs(60), POLL_INTERVAL);
+
+        assert!(matches!(token, Err(PlexError::PinExpired)));
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use reqwest::blocking::Client;
+
+use crate::plex_client::gdm::GdmServer;
+use crate::plex_client::plex_tv::Resource;
+
+const IDENTITY_PATH: &str = "/identity";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionKind {
+    Local,
+    Remote,
+    Relay,
+}
+
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub uri: String,
+    pub kind: ConnectionKind,
+    pub reachable: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub name: String,
+    pub client_identifier: String,
+    pub owned: bool,
+    pub discovered: bool,
+    pub access_token: Option<String>,
+    pub connections: Vec<Connection>,
+}
+
+impl fmt::Display for ConnectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionKind::Local => write!(f, "local"),
+            ConnectionKind::Remote => write!(f, "remote"),
+            ConnectionKind::Relay => write!(f, "relay"),
+        }
+    }
+}
+
+impl From<Resource> for Server {
+    fn from(resource: Resource) -> Self {
+        let connections = resource
+            .connections
+            .into_iter()
+            .map(|connection| Connection {
+                kind: match (connection.relay, connection.local) {
+                    (true, _) => ConnectionKind::Relay,
+                    (false, true) => ConnectionKind::Local,
+                    (false, false) => ConnectionKind::Remote,
+                },
+                uri: connection.uri,
+                reachable: None,
+            })
+            .collect();
+        Server {
+            name: resource.name,
+            client_identifier: resource.client_identifier,
+            owned: resource.owned,
+            discovered: false,
+            access_token: resource.access_token,
+            connections,
+        }
+    }
+}
+
+impl From<GdmServer> for Server {
+    fn from(gdm_server: GdmServer) -> Self {
+        Server {
+            connections: vec![Connection {
+                uri: gdm_server.uri(),
+                kind: ConnectionKind::Local,
+                reachable: None,
+            }],
+            name: gdm_server.name,
+            client_identifier: gdm_server.client_identifier,
+            owned: false,
+            discovered: true,
+            access_token: None,
+        }
+    }
+}
+
+impl Server {
+    pub fn best_connection(&self) -> Option<&Connection> {
+        self.connections
+            .iter()
+            .filter(|connection| connection.reachable != Some(false))
+            .min_by_key(|connection| connection.kind)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.name == name || self.client_identifier == name
+    }
+
+    pub fn print_informations(&self) {
+        let mut output = format!("{} ({})", self.name, self.client_identifier);
+        if self.owned {
+            output = format!("{} [owned]", output)
+        }
+        if self.discovered {
+            output = format!("{} [discovered]", output)
+        }
+        println!("\n{}", output);
+        let best_connection = self.best_connection().map(|connection| &connection.uri);
+        for connection in self.connections.iter() {
+            let marker = if Some(&connection.uri) == best_connection {
+                "*"
+            } else {
+                " "
+            };
+            match connection.reachable {
+                Some(false) => println!(
+                    "  {} {} [{}, unreachable]",
+                    marker, connection.uri, connection.kind
+                ),
+                _ => println!("  {} {} [{}]", marker, connection.uri, connection.kind),
+            }
+        }
+    }
+}
+
+pub fn merge(resources: Vec<Resource>, gdm_servers: Vec<GdmServer>) -> Vec<Server> {
+    let mut servers: Vec<Server> = resources.into_iter().map(Server::from).collect();
+    for gdm_server in gdm_servers {
+        match servers
+            .iter_mut()
+            .find(|server| server.client_identifier == gdm_server.client_identifier)
+        {
+            Some(server) => {
+                server.discovered = true;
+                let uri = gdm_server.uri();
+                if !server
+                    .connections
+                    .iter()
+                    .any(|connection| connection.uri == uri)
+                {
+                    server.connections.push(Connection {
+                        uri,
+                        kind: ConnectionKind::Local,
+                        reachable: None,
+                    });
+                }
+            }
+            None => servers.push(Server::from(gdm_server)),
+        }
+    }
+    servers
+}
+
+pub fn check_reachability(servers: &mut [Server], timeout: Duration) {
+    let http = match Client::builder().timeout(timeout).build() {
+        Ok(http) => http,
+        Err(error) => {
+            info!("Could not build client to check connections: {}", error);
+            return;
+        }
+    };
+    thread::scope(|scope| {
+        for connection in servers
+            .iter_mut()
+            .flat_map(|server| server.connections.iter_mut())
+        {
+            let http = &http;
+            scope.spawn(move || {
+                let url = format!("{}{}", connection.uri.trim_end_matches('/'), IDENTITY_PATH);
+                info!("GET {}", url);
+                connection.reachable = Some(http.get(url).send().is_ok());
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::plex_client::plex_tv::ResourceConnection;
+    use crate::test_support::{Response, TestServer};
+
+    fn resource(client_identifier: &str, connections: Vec<(&str, bool, bool)>) -> Resource {
+        Resource {
+            name: String::from("Home"),
+            client_identifier: client_identifier.to_string(),
+            provides: String::from("server"),
+            owned: true,
+            access_token: Some(String::from("SERVERTOKEN")),
+            connections: connections
+                .into_iter()
+                .map(|(uri, local, relay)| ResourceConnection {
+                    uri: uri.to_string(),
+                    local,
+                    relay,
+                })
+                .collect(),
+        }
+    }
+
+    fn gdm_server(client_identifier: &str, address: &str) -> GdmServer {
+        GdmServer {
+            name: String::from("Discovered"),
+            client_identifier: client_identifier.to_string(),
+            address: address.parse().unwrap(),
+            port: 32400,
+        }
+    }
+
+    fn uris(server: &Server) -> Vec<(&str, ConnectionKind)> {
+        server
+            .connections
+            .iter()
+            .map(|connection| (connection.uri.as_str(), connection.kind))
+            .collect()
+    }
+
+    fn closed_port_uri() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn merges_discovered_servers_into_account_servers() {
+        let resources = vec![resource(
+            "abc123",
+            vec![
+                ("https://relay.plex.direct:8443", false, true),
+                ("https://1-2-3-4.plex.direct:32400", false, false),
+            ],
+        )];
+        let gdm_servers = vec![
+            gdm_server("abc123", "192.168.1.10"),
+            gdm_server("other", "192.168.1.20"),
+        ];
+
+        let servers = merge(resources, gdm_servers);
+
+        assert_eq!(2, servers.len());
+        assert!(servers[0].owned && servers[0].discovered);
+        assert_eq!(
+            vec![
+                ("https://relay.plex.direct:8443", ConnectionKind::Relay),
+                ("https://1-2-3-4.plex.direct:32400", ConnectionKind::Remote),
+                ("http://192.168.1.10:32400", ConnectionKind::Local),
+            ],
+            uris(&servers[0])
+        );
+        assert!(!servers[1].owned && servers[1].discovered);
+        assert_eq!("Discovered", servers[1].name);
+        assert_eq!(None, servers[1].access_token);
+    }
+
+    #[test]
+    fn does_not_duplicate_known_connections() {
+        let resources = vec![resource(
+            "abc123",
+            vec![("http://192.168.1.10:32400", true, false)],
+        )];
+
+        let servers = merge(resources, vec![gdm_server("abc123", "192.168.1.10")]);
+
+        assert_eq!(
+            vec![("http://192.168.1.10:32400", ConnectionKind::Local)],
+            uris(&servers[0])
+        );
+    }
+
+    #[test]
+    fn prefers_local_then_remote_then_relay_connections() {
+        let mut server = Server::from(resource(
+            "abc123",
+            vec![
+                ("https://relay.plex.direct:8443", false, true),
+                ("https://1-2-3-4.plex.direct:32400", false, false),
+                ("http://192.168.1.10:32400", true, false),
+            ],
+        ));
+
+        assert_eq!(
+            "http://192.168.1.10:32400",
+            server.best_connection().unwrap().uri
+        );
+
+        server.connections[2].reachable = Some(false);
+        assert_eq!(
+            "https://1-2-3-4.plex.direct:32400",
+            server.best_connection().unwrap().uri
+        );
+
+        server.connections[1].reachable = Some(false);
+        server.connections[0].reachable = Some(false);
+        assert!(server.best_connection().is_none());
+    }
+
+    #[test]
+    fn checks_which_connections_answer() {
+        let test_server =
+            TestServer::start(|_| Response::new(200, "text/xml", "<MediaContainer/>"));
+        let closed_uri = closed_port_uri();
+        let mut servers = vec![Server::from(resource(
+            "abc123",
+            vec![(&closed_uri, true, false), (&test_server.url, false, false)],
+        ))];
+
+        check_reachability(&mut servers, Duration::from_secs(5));
+
+        assert_eq!(Some(false), servers[0].connections[0].reachable);
+        assert_eq!(Some(true), servers[0].connections[1].reachable);
+        assert_eq!(&test_server.url, &servers[0].best_connection().unwrap().uri);
+        assert_eq!("/identity", test_server.requests()[0].path);
+    }
+}
@@ -1,4 +1,9 @@
-use crate::m3u::{Item, Metadata, TrackData, WithMetadata};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::m3u::{ExtInf, Item, Metadata, TrackData, WithMetadata};
+use crate::rewrite::{self, RewriteRule};
+use crate::template::PathTemplate;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
@@ -14,6 +19,18 @@ pub struct MediaContainer {
 
     #[serde(rename = "@title")]
     pub title: String,
+
+    #[serde(rename = "@size")]
+    pub size: Option<u32>,
+
+    #[serde(rename = "@totalSize")]
+    pub total_size: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchMediaContainer {
+    #[serde(rename = "Track", default)]
+    pub tracks: Vec<Track>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +43,16 @@ pub struct Video {
     pub parent_title: Option<String>,
     #[serde(rename = "@grandparentTitle")]
     pub grandparent_title: Option<String>,
+    #[serde(rename = "@duration")]
+    pub duration: Option<u64>,
+    #[serde(rename = "@index")]
+    pub index: Option<u32>,
+    #[serde(rename = "@parentIndex")]
+    pub parent_index: Option<u32>,
+    #[serde(rename = "@parentYear")]
+    pub parent_year: Option<u32>,
+    #[serde(rename = "@updatedAt")]
+    pub updated_at: Option<u64>,
     #[serde(rename = "Media")]
     pub medias: Vec<Media>,
 }
@@ -40,6 +67,16 @@ pub struct Track {
     pub parent_title: Option<String>,
     #[serde(rename = "@grandparentTitle")]
     pub grandparent_title: Option<String>,
+    #[serde(rename = "@duration")]
+    pub duration: Option<u64>,
+    #[serde(rename = "@index")]
+    pub index: Option<u32>,
+    #[serde(rename = "@parentIndex")]
+    pub parent_index: Option<u32>,
+    #[serde(rename = "@parentYear")]
+    pub parent_year: Option<u32>,
+    #[serde(rename = "@updatedAt")]
+    pub updated_at: Option<u64>,
     #[serde(rename = "Media")]
     pub medias: Vec<Media>,
 }
@@ -58,6 +95,18 @@ pub struct Part {
     pub key: String,
     #[serde(rename = "@file")]
     pub file: String,
+    #[serde(rename = "@size")]
+    pub size: Option<u64>,
+    #[serde(rename = "@duration")]
+    pub duration: Option<u64>,
+}
+
+impl Part {
+    pub fn extension(&self) -> Option<String> {
+        Path::new(&self.file)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+    }
 }
 
 impl WithMetadata for MediaContainer {
@@ -75,6 +124,12 @@ pub trait WithMedia {
 
     fn medias(&self) -> Vec<Media>;
 
+    fn ext_inf(&self, duration: Option<u64>) -> ExtInf;
+
+    fn template_fields(&self) -> HashMap<&str, Option<String>>;
+
+    fn updated_at(&self) -> Option<u64>;
+
     fn print_informations(&self) {
         println!("\nKey: {}", self.key());
         for (title, value) in self.informations() {
@@ -91,33 +146,98 @@ pub trait WithMedia {
         }
     }
 
-    fn files(&self, rewrite_from: &Option<String>, rewrite_to: &Option<String>) -> Vec<Item> {
-        let mut files: Vec<Item> = vec![];
-        for media in self.medias().iter() {
-            for part in media.parts.iter() {
-                let mut file_name = part.file.clone();
-                file_name = match rewrite_from {
-                    Some(string) => {
-                        let to = rewrite_to.clone().unwrap_or("".to_string());
-                        file_name.replace(string, &to)
-                    }
-                    None => file_name,
-                };
-                let metadata = vec![TrackData::Key(part.key.clone())];
-                let item = Item::new(file_name, metadata);
-                files.push(item)
+    fn files(
+        &self,
+        rewrite_rules: &[RewriteRule],
+        template: Option<&PathTemplate>,
+    ) -> Vec<(String, Item)> {
+        let mut files = vec![];
+        let medias = self.medias();
+        let parts = medias.iter().flat_map(|media| media.parts.iter());
+        for (index, part) in parts.enumerate() {
+            let file_name = match template {
+                Some(template) => {
+                    let mut fields = self.template_fields();
+                    fields.insert("ext", part.extension());
+                    template.render(&fields)
+                }
+                None => rewrite::apply_all(rewrite_rules, &part.file),
+            };
+            let mut metadata = vec![TrackData::Key(part.key.clone())];
+            if let Some(size) = part.size {
+                metadata.push(TrackData::Size(size));
+            }
+            if let Some(duration) = part.duration {
+                metadata.push(TrackData::Duration(duration));
             }
+            if let Some(updated_at) = self.updated_at() {
+                metadata.push(TrackData::UpdatedAt(updated_at));
+            }
+            metadata.push(TrackData::Info(self.ext_inf(part.duration)));
+            let item = Item::new(file_name, metadata);
+            files.push((format!("{}/{}", self.key(), index), item))
         }
 
         files
     }
 }
 
+fn template_fields(
+    title: &str,
+    parent_title: &Option<String>,
+    grandparent_title: &Option<String>,
+    index: Option<u32>,
+    parent_index: Option<u32>,
+    parent_year: Option<u32>,
+) -> HashMap<&'static str, Option<String>> {
+    HashMap::from([
+        ("artist", grandparent_title.clone()),
+        ("album", parent_title.clone()),
+        ("title", Some(title.to_string())),
+        ("track", index.map(|index| index.to_string())),
+        ("disc", parent_index.map(|index| index.to_string())),
+        ("year", parent_year.map(|year| year.to_string())),
+    ])
+}
+
+impl Track {
+    pub fn artist(&self) -> Option<&str> {
+        self.grandparent_title.as_deref()
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.parent_title.as_deref()
+    }
+}
+
 impl WithMedia for Track {
     fn medias(&self) -> Vec<Media> {
         self.medias.clone()
     }
 
+    fn ext_inf(&self, duration: Option<u64>) -> ExtInf {
+        ExtInf::new(
+            self.duration.or(duration),
+            self.grandparent_title.clone(),
+            self.title.clone(),
+        )
+    }
+
+    fn updated_at(&self) -> Option<u64> {
+        self.updated_at
+    }
+
+    fn template_fields(&self) -> HashMap<&str, Option<String>> {
+        template_fields(
+            &self.title,
+            &self.parent_title,
+            &self.grandparent_title,
+            self.index,
+            self.parent_index,
+            self.parent_year,
+        )
+    }
+
     fn key(&self) -> String {
         self.rating_key.to_string()
     }
@@ -136,6 +256,29 @@ impl WithMedia for Video {
         self.medias.clone()
     }
 
+    fn ext_inf(&self, duration: Option<u64>) -> ExtInf {
+        ExtInf::new(
+            self.duration.or(duration),
+            self.grandparent_title.clone(),
+            self.title.clone(),
+        )
+    }
+
+    fn updated_at(&self) -> Option<u64> {
+        self.updated_at
+    }
+
+    fn template_fields(&self) -> HashMap<&str, Option<String>> {
+        template_fields(
+            &self.title,
+            &self.parent_title,
+            &self.grandparent_title,
+            self.index,
+            self.parent_index,
+            self.parent_year,
+        )
+    }
+
     fn key(&self) -> String {
         self.rating_key.to_string()
     }
@@ -150,19 +293,36 @@ impl WithMedia for Video {
 }
 
 impl MediaContainer {
+    pub fn item_count(&self) -> u32 {
+        (self.tracks.len() + self.videos.len()) as u32
+    }
+
+    pub fn rating_keys(&self) -> impl Iterator<Item = u32> + '_ {
+        self.tracks
+            .iter()
+            .map(|track| track.rating_key)
+            .chain(self.videos.iter().map(|video| video.rating_key))
+    }
+
+    pub fn fetched_count(&self) -> u32 {
+        self.size.unwrap_or_else(|| self.item_count())
+    }
+
+    /// Files are paired with the rating key and part index they come from, which
+    /// stays the same when a part is upgraded.
     pub fn track_files(
         &self,
-        rewrite_from: Option<String>,
-        rewrite_to: Option<String>,
-    ) -> Vec<Item> {
-        let mut files: Vec<Item> = vec![];
+        rewrite_rules: &[RewriteRule],
+        template: Option<&PathTemplate>,
+    ) -> Vec<(String, Item)> {
+        let mut files = vec![];
         for track in self.tracks.iter() {
-            for file in track.files(&rewrite_from, &rewrite_to).iter() {
+            for file in track.files(rewrite_rules, template).iter() {
                 files.push(file.clone());
             }
         }
         for video in self.videos.iter() {
-            for file in video.files(&rewrite_from, &rewrite_to).iter() {
+            for file in video.files(rewrite_rules, template).iter() {
                 files.push(file.clone());
             }
         }
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::download;
+use crate::m3u::{self, Item};
+use crate::paths::{self, PathOptions};
+
+const PART_EXTENSION: &str = "part";
+const PLAYLIST_EXTENSIONS: [&str; 2] = ["m3u", "m3u8"];
+const MEDIA_EXTENSIONS: [&str; 17] = [
+    "aac", "aif", "aiff", "alac", "ape", "avi", "flac", "m4a", "m4b", "m4v", "mkv", "mov", "mp3",
+    "mp4", "ogg", "opus", "wav",
+];
+
+pub struct Orphan {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct References {
+    files: Vec<PathBuf>,
+    playlists: Vec<PathBuf>,
+    unresolved: Vec<String>,
+}
+
+impl References {
+    pub fn add_playlist(
+        &mut self,
+        name: &str,
+        file: &Path,
+        tracks: &[Item],
+        root_path: &Path,
+        path_options: &PathOptions,
+    ) {
+        let files: Vec<PathBuf> = tracks
+            .iter()
+            .filter_map(|track| downloaded_path(track.local_path(root_path, path_options)))
+            .collect();
+        if files.is_empty() && !tracks.is_empty() {
+            self.unresolved.push(name.to_string());
+        }
+        self.files.extend(files);
+        self.playlists.push(file.to_path_buf());
+    }
+
+    pub fn add_files(&mut self, files: Vec<PathBuf>) {
+        self.files.extend(files);
+    }
+}
+
+pub fn find_orphans(root_path: &Path, references: &References) -> io::Result<Vec<Orphan>> {
+    if !references.unresolved.is_empty() {
+        return Err(io::Error::other(format!(
+            "no track of {} found under {:?}",
+            references.unresolved.join(", "),
+            root_path
+        )));
+    }
+
+    let files = paths::walk(root_path)?;
+    let synced_playlists: HashSet<PathBuf> = references
+        .playlists
+        .iter()
+        .filter_map(|playlist| fs::canonicalize(playlist).ok())
+        .collect();
+    let mut referenced: HashSet<PathBuf> = references
+        .files
+        .iter()
+        .filter_map(|file| fs::canonicalize(file).ok())
+        .collect();
+    for playlist in files
+        .iter()
+        .filter(|file| has_extension(file, &PLAYLIST_EXTENSIONS))
+        .filter(|file| !synced_playlists.contains(&fs::canonicalize(file).unwrap_or_default()))
+    {
+        let playlist_directory = playlist.parent().unwrap_or(root_path);
+        for track in m3u::read(playlist)?.tracks {
+            let full_path = track.full_path(playlist_directory);
+            if let Some(path) = paths::locate(&full_path).or_else(|| downloaded_path(full_path))
+                && let Ok(path) = fs::canonicalize(path)
+            {
+                referenced.insert(path);
+            }
+        }
+    }
+    if referenced.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no playlist references a file under {:?}", root_path),
+        ));
+    }
+
+    let mut orphans = vec![];
+    for file in files
+        .iter()
+        .filter(|file| is_media(file) || is_partial_media(file))
+    {
+        if !referenced.contains(&fs::canonicalize(file)?) {
+            orphans.push(Orphan {
+                path: file.clone(),
+                size: fs::metadata(file)?.len(),
+            });
+        }
+    }
+    orphans.sort_by(|left, right| left.path.cmp(&right.path));
+    Ok(orphans)
+}
+
+pub fn remove_empty_directories(root_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    for entry in fs::read_dir(root_path)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            removed.extend(remove_empty_directories(&path)?);
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+                removed.push(path);
+            }
+        }
+    }
+    Ok(removed)
+}
+
+pub fn is_media(path: &Path) -> bool {
+    has_extension(path, &MEDIA_EXTENSIONS)
+}
+
+fn is_partial_media(path: &Path) -> bool {
+    has_extension(path, &[PART_EXTENSION]) && is_media(&path.with_extension(""))
+}
+
+fn downloaded_path(full_path: PathBuf) -> Option<PathBuf> {
+    if full_path.exists() {
+        return Some(full_path);
+    }
+    let part_path = download::part_path(&full_path);
+    part_path.exists().then_some(part_path)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| extensions.contains(&extension.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn item(path: &str) -> Item {
+        Item::new(path.to_string(), vec![])
+    }
+
+    fn orphan_paths(root: &TempDir, references: &References) -> Vec<PathBuf> {
+        find_orphans(root.path(), references)
+            .unwrap()
+            .into_iter()
+            .map(|orphan| orphan.path)
+            .collect()
+    }
+
+    #[test]
+    fn resolves_synced_tracks_against_the_track_root() {
+        let root = TempDir::new();
+        let playlist = root.file("Playlists/Road Trip.m3u", b"content");
+        root.file("Music/Rush/Song.mp3", b"content");
+        let orphan = root.file("Music/Gone.mp3", b"content");
+        let mut references = References::default();
+        references.add_playlist(
+            "1",
+            &playlist,
+            &[item("Music/Rush/Song.mp3")],
+            root.path(),
+            &PathOptions::default(),
+        );
+
+        assert_eq!(vec![orphan], orphan_paths(&root, &references));
+    }
+
+    #[test]
+    fn keeps_files_referenced_by_other_playlists() {
+        let root = TempDir::new();
+        let playlist = root.file("Synced.m3u", b"content");
+        root.file("Other.m3u", b"#EXTM3U\nOther.mp3\n");
+        root.file("Other.mp3", b"content");
+        root.file("Synced.mp3", b"content");
+        let orphan = root.file("Gone.flac", b"content");
+        let mut references = References::default();
+        references.add_playlist(
+            "1",
+            &playlist,
+            &[item("Synced.mp3")],
+            root.path(),
+            &PathOptions::default(),
+        );
+
+        assert_eq!(vec![orphan], orphan_paths(&root, &references));
+    }
+
+    #[test]
+    fn ignores_the_previous_content_of_synced_playlists() {
+        let root = TempDir::new();
+        let playlist = root.file("Synced.m3u", b"#EXTM3U\nRemoved.mp3\nKept.mp3\n");
+        root.file("Kept.mp3", b"content");
+        let orphan = root.file("Removed.mp3", b"content");
+        let mut references = References::default();
+        references.add_playlist(
+            "1",
+            &playlist,
+            &[item("Kept.mp3")],
+            root.path(),
+            &PathOptions::default(),
+        );
+
+        assert_eq!(vec![orphan], orphan_paths(&root, &references));
+    }
+
+    #[test]
+    fn refuses_to_prune_when_a_playlist_resolves_no_track() {
+        let root = TempDir::new();
+        let playlist = root.file("Synced.m3u", b"content");
+        root.file("Kept.mp3", b"content");
+        root.file("Elsewhere.mp3", b"content");
+        let mut references = References::default();
+        references.add_playlist(
+            "1",
+            &playlist,
+            &[item("Kept.mp3")],
+            root.path(),
+            &PathOptions::default(),
+        );
+        references.add_playlist(
+            "2",
+            &playlist,
+            &[item("/media/device/Elsewhere.mp3")],
+            root.path(),
+            &PathOptions::default(),
+        );
+
+        assert!(find_orphans(root.path(), &references).is_err());
+    }
+
+    #[test]
+    fn refuses_to_prune_when_nothing_is_referenced() {
+        let root = TempDir::new();
+        root.file("Song.mp3", b"content");
+
+        assert!(find_orphans(root.path(), &References::default()).is_err());
+    }
+
+    #[test]
+    fn keeps_partial_downloads_of_referenced_tracks_only() {
+        let root = TempDir::new();
+        let playlist = root.file("Synced.m3u", b"content");
+        root.file("Kept.mp3", b"content");
+        root.file("Pending.mp3.part", b"content");
+        let orphan = root.file("Gone.mp3.part", b"content");
+        root.file("Notes.txt.part", b"content");
+        let mut references = References::default();
+        references.add_playlist(
+            "1",
+            &playlist,
+            &[item("Kept.mp3"), item("Pending.mp3")],
+            root.path(),
+            &PathOptions::default(),
+        );
+
+        assert_eq!(vec![orphan], orphan_paths(&root, &references));
+    }
+}
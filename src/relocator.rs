@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::m3u::Item;
+use crate::paths;
+use crate::resolver::normalize;
+
+pub enum Relocation {
+    Found(PathBuf),
+    Ambiguous(Vec<PathBuf>),
+    NotFound,
+}
+
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+}
+
+pub struct Relocator {
+    files: HashMap<String, Vec<LocalFile>>,
+}
+
+impl Relocator {
+    pub fn scan(root_path: &Path) -> io::Result<Relocator> {
+        let mut files: HashMap<String, Vec<LocalFile>> = HashMap::new();
+        for file in paths::walk(root_path)? {
+            let (Some(file_name), Ok(path)) = (file.file_name(), file.strip_prefix(root_path))
+            else {
+                continue;
+            };
+            files
+                .entry(normalize(&file_name.to_string_lossy()))
+                .or_default()
+                .push(LocalFile {
+                    path: path.to_path_buf(),
+                    size: fs::metadata(&file)?.len(),
+                });
+        }
+        Ok(Relocator { files })
+    }
+
+    pub fn relocate(&self, item: &Item) -> Relocation {
+        let segments: Vec<&str> = item
+            .path
+            .split(['/', '\\'])
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let Some((file_name, directories)) = segments.split_last() else {
+            return Relocation::NotFound;
+        };
+        let Some(local_files) = self.files.get(&normalize(file_name)) else {
+            return Relocation::NotFound;
+        };
+
+        let expected_size = item.size();
+        let mut best_score = 0;
+        let mut best_paths = vec![];
+        for local_file in local_files.iter() {
+            if expected_size.is_some_and(|size| size != local_file.size) {
+                continue;
+            }
+            let score = directory_score(directories, &local_file.path);
+            if score == 0 && expected_size.is_none() {
+                continue;
+            }
+            if score > best_score || best_paths.is_empty() {
+                best_score = score;
+                best_paths = vec![local_file.path.clone()];
+            } else if score == best_score {
+                best_paths.push(local_file.path.clone());
+            }
+        }
+
+        match best_paths.len() {
+            0 => Relocation::NotFound,
+            1 => Relocation::Found(best_paths.remove(0)),
+            _ => Relocation::Ambiguous(best_paths),
+        }
+    }
+}
+
+fn directory_score(expected: &[&str], path: &Path) -> u32 {
+    let actual: Vec<String> = path
+        .parent()
+        .map(|parent| {
+            parent
+                .iter()
+                .map(|segment| normalize(&segment.to_string_lossy()))
+                .collect()
+        })
+        .unwrap_or_default();
+    expected
+        .iter()
+        .rev()
+        .zip(actual.iter().rev())
+        .map(|(expected, actual)| {
+            let expected = normalize(expected);
+            if expected.is_empty() || actual.is_empty() {
+                0
+            } else if expected == *actual {
+                2
+            } else if expected.contains(actual.as_str()) || actual.contains(&expected) {
+                1
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::m3u::TrackData;
+    use crate::test_support::TempDir;
+
+    fn item(path: &str, size: Option<u64>) -> Item {
+        Item::new(
+            path.to_string(),
+            size.map(TrackData::Size).into_iter().collect(),
+        )
+    }
+
+    fn relocate(root: &TempDir, item: &Item) -> Relocation {
+        Relocator::scan(root.path()).unwrap().relocate(item)
+    }
+
+    fn found(relocation: Relocation) -> PathBuf {
+        match relocation {
+            Relocation::Found(path) => path,
+            Relocation::Ambiguous(paths) => panic!("ambiguous between {:?}", paths),
+            Relocation::NotFound => panic!("not found"),
+        }
+    }
+
+    #[test]
+    fn finds_files_by_normalized_file_name() {
+        let root = TempDir::new();
+        root.file("Rush/Power Windows/03 manhattan_project.m4a", b"song");
+
+        let relocation = relocate(
+            &root,
+            &item("/old/Rush/Power Windows/03 Manhattan Project.m4a", None),
+        );
+
+        assert_eq!(
+            Path::new("Rush/Power Windows/03 manhattan_project.m4a"),
+            found(relocation)
+        );
+    }
+
+    #[test]
+    fn only_keeps_files_of_the_expected_size() {
+        let root = TempDir::new();
+        root.file("Rush/Power Windows/03 Manhattan Project.m4a", b"truncated");
+        root.file("Other/03 Manhattan Project.m4a", b"song");
+
+        let relocation = relocate(
+            &root,
+            &item("/old/Rush/Power Windows/03 Manhattan Project.m4a", Some(4)),
+        );
+
+        assert_eq!(
+            Path::new("Other/03 Manhattan Project.m4a"),
+            found(relocation)
+        );
+    }
+
+    #[test]
+    fn prefers_files_in_matching_directories() {
+        let root = TempDir::new();
+        root.file("Rush/Power Windows/01 Intro.m4a", b"song");
+        root.file("Rush/Live/01 Intro.m4a", b"song");
+        root.file("Various/Power Windows (Remaster)/01 Intro.m4a", b"song");
+
+        let relocation = relocate(&root, &item("/old/Rush/Power Windows/01 Intro.m4a", None));
+
+        assert_eq!(
+            Path::new("Rush/Power Windows/01 Intro.m4a"),
+            found(relocation)
+        );
+        assert_eq!(
+            4,
+            directory_score(
+                &["Rush", "Power Windows"],
+                Path::new("Rush/Power Windows/a.m4a")
+            )
+        );
+        assert_eq!(
+            1,
+            directory_score(
+                &["Rush", "Power Windows"],
+                Path::new("Various/Power Windows (Remaster)/a.m4a")
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_ambiguous_files_unchanged() {
+        let root = TempDir::new();
+        root.file("Disc 1/Rush/01 Intro.m4a", b"song");
+        root.file("Disc 2/Rush/01 Intro.m4a", b"song");
+
+        let relocation = relocate(&root, &item("/old/Rush/01 Intro.m4a", None));
+
+        match relocation {
+            Relocation::Ambiguous(mut paths) => {
+                paths.sort();
+                assert_eq!(
+                    vec![
+                        PathBuf::from("Disc 1/Rush/01 Intro.m4a"),
+                        PathBuf::from("Disc 2/Rush/01 Intro.m4a")
+                    ],
+                    paths
+                );
+            }
+            _ => panic!("expected an ambiguous relocation"),
+        }
+    }
+
+    #[test]
+    fn does_not_guess_without_directory_or_size_match() {
+        let root = TempDir::new();
+        root.file("Somewhere/01 Intro.m4a", b"song");
+
+        assert!(matches!(
+            relocate(&root, &item("/old/Rush/Power Windows/01 Intro.m4a", None)),
+            Relocation::NotFound
+        ));
+        assert!(matches!(
+            relocate(&root, &item("/old/Rush/Power Windows/02 Outro.m4a", None)),
+            Relocation::NotFound
+        ));
+    }
+
+    #[test]
+    fn relocates_size_matches_in_other_directories() {
+        let root = TempDir::new();
+        root.file("Somewhere/01 Intro.m4a", b"song");
+
+        let relocation = relocate(
+            &root,
+            &item("/old/Rush/Power Windows/01 Intro.m4a", Some(4)),
+        );
+
+        assert_eq!(Path::new("Somewhere/01 Intro.m4a"), found(relocation));
+    }
+}
@@ -0,0 +1,426 @@
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::m3u::{Item, TrackData};
+use crate::plex_client::track::{Part, Track};
+use crate::plex_client::{PlexClient, PlexError};
+
+const TITLE_WEIGHT: f64 = 0.6;
+const ARTIST_WEIGHT: f64 = 0.25;
+const ALBUM_WEIGHT: f64 = 0.15;
+const DURATION_TOLERANCE: u64 = 5000;
+const DURATION_PENALTY: f64 = 0.75;
+
+#[derive(Debug)]
+pub struct Query {
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Candidate {
+    pub track: Track,
+    pub part: Part,
+    pub confidence: u8,
+}
+
+#[derive(Debug)]
+pub enum Resolution {
+    Match(Box<Candidate>),
+    Ambiguous(Vec<Candidate>),
+    NoMatch,
+}
+
+impl Query {
+    pub fn from_item(item: &Item) -> Option<Query> {
+        let segments: Vec<&str> = item
+            .path
+            .split(['/', '\\'])
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let (path_artist, path_album) = match segments.as_slice() {
+            [.., artist, album, _] => (Some(artist.to_string()), Some(album.to_string())),
+            _ => (None, None),
+        };
+        let path_title = segments
+            .last()
+            .map(|file_name| title_from_file_name(file_name));
+
+        let mut title = None;
+        let mut artist = None;
+        let mut album = None;
+        let mut duration = None;
+        for track_data in item.metadata.iter() {
+            match track_data {
+                TrackData::Info(ext_inf) => {
+                    title = Some(ext_inf.title.clone());
+                    artist = artist.or(ext_inf.artist.clone());
+                    if ext_inf.duration > 0 {
+                        duration = duration.or(Some(ext_inf.duration as u64 * 1000));
+                    }
+                }
+                TrackData::Artist(value) => artist = Some(value.clone()),
+                TrackData::Album(value) => album = Some(value.clone()),
+                TrackData::Duration(value) => duration = Some(*value),
+                _ => (),
+            }
+        }
+
+        Some(Query {
+            title: title
+                .or(path_title)
+                .filter(|title| !title.trim().is_empty())?,
+            artist: artist.or(path_artist),
+            album: album.or(path_album),
+            duration,
+        })
+    }
+
+    fn score(&self, track: &Track) -> u8 {
+        let title_similarity = similarity(&self.title, &track.title);
+        if title_similarity == 0.0 {
+            return 0;
+        }
+        // Unknown artist or album count as mismatches, a title alone is never enough.
+        let mut confidence = TITLE_WEIGHT * title_similarity;
+        if let Some(artist) = &self.artist {
+            confidence += ARTIST_WEIGHT * similarity(artist, track.artist().unwrap_or_default());
+        }
+        if let Some(album) = &self.album {
+            confidence += ALBUM_WEIGHT * similarity(album, track.album().unwrap_or_default());
+        }
+        if let (Some(expected), Some(actual)) = (self.duration, track.duration)
+            && expected.abs_diff(actual) > DURATION_TOLERANCE
+        {
+            confidence *= DURATION_PENALTY;
+        }
+        (confidence * 100.0).round() as u8
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(
+            f,
+            &self.title,
+            self.artist.as_deref(),
+            self.album.as_deref(),
+        )
+    }
+}
+
+impl Candidate {
+    pub fn resolved_item(&self, item: &Item) -> Item {
+        let mut metadata = vec![TrackData::Key(self.part.key.clone())];
+        if let Some(size) = self.part.size {
+            metadata.push(TrackData::Size(size));
+        }
+        metadata.extend(
+            item.metadata
+                .iter()
+                .filter(|track_data| !matches!(track_data, TrackData::Key(_) | TrackData::Size(_)))
+                .cloned(),
+        );
+        Item::new(item.path.clone(), metadata)
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(
+            f,
+            &self.track.title,
+            self.track.artist(),
+            self.track.album(),
+        )?;
+        write!(f, " [{}%]", self.confidence)
+    }
+}
+
+pub fn resolve(plex_client: &PlexClient, query: &Query) -> Result<Resolution, PlexError> {
+    let tracks = plex_client.search_tracks(&query.title)?;
+    let candidates: Vec<Candidate> = tracks
+        .into_iter()
+        .filter_map(|track| {
+            let part = track
+                .medias
+                .iter()
+                .flat_map(|media| media.parts.iter())
+                .next()?
+                .clone();
+            Some(Candidate {
+                confidence: query.score(&track),
+                track,
+                part,
+            })
+        })
+        .filter(|candidate| candidate.confidence > 0)
+        .collect();
+    let Some(best) = candidates
+        .iter()
+        .map(|candidate| candidate.confidence)
+        .max()
+    else {
+        return Ok(Resolution::NoMatch);
+    };
+
+    let mut best_candidates: Vec<Candidate> = candidates
+        .into_iter()
+        .filter(|candidate| candidate.confidence == best)
+        .collect();
+    match best_candidates.len() {
+        1 => Ok(Resolution::Match(Box::new(best_candidates.remove(0)))),
+        _ => Ok(Resolution::Ambiguous(best_candidates)),
+    }
+}
+
+fn describe(
+    f: &mut fmt::Formatter,
+    title: &str,
+    artist: Option<&str>,
+    album: Option<&str>,
+) -> fmt::Result {
+    if let Some(artist) = artist {
+        write!(f, "{} - ", artist)?;
+    }
+    write!(f, "{}", title)?;
+    if let Some(album) = album {
+        write!(f, " ({})", album)?;
+    }
+    Ok(())
+}
+
+fn title_from_file_name(file_name: &str) -> String {
+    let stem = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= 5 => stem,
+        _ => file_name,
+    };
+    let title = strip_track_number(stem);
+    if title.is_empty() { stem } else { title }.to_string()
+}
+
+fn strip_track_number(stem: &str) -> &str {
+    let digits_end = stem
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(stem.len());
+    if digits_end == 0 {
+        return stem;
+    }
+    let rest = &stem[digits_end..];
+    let spaced = rest.trim_start_matches(' ');
+    let title = match spaced.strip_prefix(['-', '.', '_']) {
+        Some(title) => title,
+        None if stem.starts_with('0') && spaced.len() < rest.len() => spaced,
+        None => return stem,
+    };
+    title.trim_start_matches([' ', '-', '.', '_'])
+}
+
+pub fn normalize(value: &str) -> String {
+    value
+        .nfc()
+        .collect::<String>()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn similarity(expected: &str, actual: &str) -> f64 {
+    let expected = normalize(expected);
+    let actual = normalize(actual);
+    if expected.is_empty() || actual.is_empty() {
+        0.0
+    } else if expected == actual {
+        1.0
+    } else if expected.contains(&actual) || actual.contains(&expected) {
+        0.5
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::m3u::ExtInf;
+    use crate::plex_client::track::SearchMediaContainer;
+    use crate::test_support::{Response, TestServer};
+
+    fn track(rating_key: u32, title: &str, artist: &str, album: &str) -> String {
+        format!(
+            r#"<Track ratingKey="{rating_key}" title="{title}" grandparentTitle="{artist}" parentTitle="{album}" duration="305000"><Media id="{rating_key}"><Part id="{rating_key}" key="/library/parts/{rating_key}/file.m4a" file="/music/{rating_key}.m4a"/></Media></Track>"#
+        )
+    }
+
+    fn parse_track(xml: &str) -> Track {
+        quick_xml::de::from_str::<SearchMediaContainer>(&format!(
+            "<MediaContainer>{}</MediaContainer>",
+            xml
+        ))
+        .unwrap()
+        .tracks
+        .remove(0)
+    }
+
+    fn query(title: &str, artist: Option<&str>, album: Option<&str>) -> Query {
+        Query {
+            title: title.to_string(),
+            artist: artist.map(str::to_string),
+            album: album.map(str::to_string),
+            duration: None,
+        }
+    }
+
+    fn resolve_with(query: &Query, tracks: Vec<String>) -> Resolution {
+        let body = format!("<MediaContainer>{}</MediaContainer>", tracks.concat());
+        let server = TestServer::start(move |_| Response::new(200, "text/xml", body.clone()));
+        let plex_client = PlexClient::new(server.url.clone(), None);
+        resolve(&plex_client, query).unwrap()
+    }
+
+    #[test]
+    fn scores_full_matches() {
+        let track = parse_track(&track(1, "Manhattan Project", "Rush", "Power Windows"));
+
+        let query = query("Manhattan Project", Some("Rush"), Some("Power Windows"));
+
+        assert_eq!(100, query.score(&track));
+    }
+
+    #[test]
+    fn does_not_trust_titles_alone() {
+        let track = parse_track(&track(1, "Manhattan Project", "Rush", "Power Windows"));
+
+        assert_eq!(60, query("Manhattan Project", None, None).score(&track));
+        assert_eq!(
+            85,
+            query("Manhattan Project", Some("Rush"), None).score(&track)
+        );
+        assert_eq!(
+            60,
+            query("Manhattan Project", Some("Someone Else"), None).score(&track)
+        );
+    }
+
+    #[test]
+    fn scores_partial_and_wrong_titles() {
+        let track = parse_track(&track(1, "Manhattan Project", "Rush", "Power Windows"));
+
+        assert_eq!(
+            70,
+            query(
+                "Manhattan Project (Remastered)",
+                Some("Rush"),
+                Some("Power Windows")
+            )
+            .score(&track)
+        );
+        assert_eq!(
+            0,
+            query("Tom Sawyer", Some("Rush"), Some("Power Windows")).score(&track)
+        );
+    }
+
+    #[test]
+    fn penalizes_different_durations() {
+        let track = parse_track(&track(1, "Manhattan Project", "Rush", "Power Windows"));
+        let mut query = query("Manhattan Project", Some("Rush"), Some("Power Windows"));
+
+        query.duration = Some(307000);
+        assert_eq!(100, query.score(&track));
+        query.duration = Some(200000);
+        assert_eq!(75, query.score(&track));
+    }
+
+    #[test]
+    fn builds_queries_from_playlist_items() {
+        let item = Item::new(
+            String::from("Music/Rush/Power Windows/03 Manhattan Project.m4a"),
+            vec![TrackData::Info(ExtInf::new(
+                Some(305000),
+                None,
+                String::from("Manhattan Project"),
+            ))],
+        );
+
+        let query = Query::from_item(&item).unwrap();
+
+        assert_eq!("Manhattan Project", query.title);
+        assert_eq!(Some("Rush"), query.artist.as_deref());
+        assert_eq!(Some("Power Windows"), query.album.as_deref());
+        assert_eq!(Some(305000), query.duration);
+    }
+
+    #[test]
+    fn resolves_the_best_candidate() {
+        let resolution = resolve_with(
+            &query("Manhattan Project", Some("Rush"), None),
+            vec![
+                track(1, "Manhattan Project", "Someone Else", "Other"),
+                track(2, "Manhattan Project", "Rush", "Power Windows"),
+                track(3, "Tom Sawyer", "Rush", "Moving Pictures"),
+            ],
+        );
+
+        match resolution {
+            Resolution::Match(candidate) => {
+                assert_eq!(2, candidate.track.rating_key);
+                assert_eq!(85, candidate.confidence);
+                assert_eq!("/library/parts/2/file.m4a", candidate.part.key);
+            }
+            resolution => panic!("expected a match, got {:?}", resolution),
+        }
+    }
+
+    #[test]
+    fn reports_tied_candidates() {
+        let resolution = resolve_with(
+            &query("Manhattan Project", None, None),
+            vec![
+                track(1, "Manhattan Project", "Someone Else", "Other"),
+                track(2, "Manhattan Project", "Rush", "Power Windows"),
+            ],
+        );
+
+        match resolution {
+            Resolution::Ambiguous(candidates) => assert_eq!(
+                vec![1, 2],
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.track.rating_key)
+                    .collect::<Vec<u32>>()
+            ),
+            resolution => panic!("expected a tie, got {:?}", resolution),
+        }
+    }
+
+    #[test]
+    fn resolves_nothing_without_matching_titles() {
+        let resolution = resolve_with(
+            &query("Manhattan Project", Some("Rush"), None),
+            vec![track(3, "Tom Sawyer", "Rush", "Moving Pictures")],
+        );
+
+        assert!(matches!(resolution, Resolution::NoMatch));
+    }
+
+    #[test]
+    fn strips_track_number_prefixes() {
+        assert_eq!("Title", title_from_file_name("01 - Title.mp3"));
+        assert_eq!("Title", title_from_file_name("1. Title.flac"));
+        assert_eq!("Title", title_from_file_name("07_Title.m4a"));
+        assert_eq!("Title", title_from_file_name("03 Title.mp3"));
+    }
+
+    #[test]
+    fn keeps_titles_starting_with_numbers() {
+        assert_eq!("99 Problems", title_from_file_name("99 Problems.mp3"));
+        assert_eq!("1979", title_from_file_name("1979.mp3"));
+        assert_eq!("99 Problems", title_from_file_name("12 - 99 Problems.mp3"));
+    }
+}
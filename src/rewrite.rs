@@ -0,0 +1,159 @@
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+
+const ARROW: &str = "=>";
+const SEPARATORS: [char; 2] = ['/', '\\'];
+
+#[derive(Debug, Clone)]
+pub enum RewriteRule {
+    Prefix { from: String, to: String },
+    Literal { from: String, to: String },
+    Regex { from: Regex, to: String },
+}
+
+impl RewriteRule {
+    pub fn apply(&self, path: &str) -> String {
+        match self {
+            RewriteRule::Prefix { from, to } => match path.strip_prefix(from.as_str()) {
+                Some(rest) if is_segment_boundary(from, rest) => format!("{}{}", to, rest),
+                _ => path.to_string(),
+            },
+            RewriteRule::Literal { from, to } => path.replace(from.as_str(), to),
+            RewriteRule::Regex { from, to } => from.replace_all(path, to.as_str()).to_string(),
+        }
+    }
+}
+
+fn is_segment_boundary(prefix: &str, rest: &str) -> bool {
+    rest.is_empty() || prefix.ends_with(SEPARATORS) || rest.starts_with(SEPARATORS)
+}
+
+pub fn apply_all(rules: &[RewriteRule], path: &str) -> String {
+    rules
+        .iter()
+        .fold(path.to_string(), |path, rule| rule.apply(&path))
+}
+
+impl FromStr for RewriteRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rewrite rule {}, expected KIND:FROM=>TO", value);
+        let (kind, rule) = value.split_once(':').ok_or_else(invalid)?;
+        let (from, to) = rule.split_once(ARROW).ok_or_else(invalid)?;
+        let (from, to) = (from.to_string(), to.to_string());
+        match kind {
+            "prefix" => Ok(RewriteRule::Prefix { from, to }),
+            "literal" => Ok(RewriteRule::Literal { from, to }),
+            "regex" => match Regex::new(&from) {
+                Ok(from) => Ok(RewriteRule::Regex { from, to }),
+                Err(error) => Err(format!("invalid regex {}: {}", from, error)),
+            },
+            _ => Err(format!(
+                "unknown rewrite kind {}, expected prefix, literal or regex",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RewriteRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RewriteRule::Prefix { from, to } => write!(f, "prefix:{}{}{}", from, ARROW, to),
+            RewriteRule::Literal { from, to } => write!(f, "literal:{}{}{}", from, ARROW, to),
+            RewriteRule::Regex { from, to } => write!(f, "regex:{}{}{}", from, ARROW, to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(value: &str) -> RewriteRule {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn rewrites_prefixes_at_segment_boundaries() {
+        let rule = rule("prefix:/music/iTunes=>Music");
+
+        assert_eq!("Music/Rush/a.m4a", rule.apply("/music/iTunes/Rush/a.m4a"));
+        assert_eq!("Music", rule.apply("/music/iTunes"));
+        assert_eq!(
+            "Music\\Rush\\a.m4a",
+            rule.apply("/music/iTunes\\Rush\\a.m4a")
+        );
+        assert_eq!(
+            "/music/iTunesOld/Rush/a.m4a",
+            rule.apply("/music/iTunesOld/Rush/a.m4a")
+        );
+        assert_eq!(
+            "/other/music/iTunes/a.m4a",
+            rule.apply("/other/music/iTunes/a.m4a")
+        );
+    }
+
+    #[test]
+    fn rewrites_prefixes_ending_with_a_separator() {
+        let rule = rule("prefix:/music/iTunes/=>Music/");
+
+        assert_eq!("Music/Rush/a.m4a", rule.apply("/music/iTunes/Rush/a.m4a"));
+    }
+
+    #[test]
+    fn rewrites_every_literal_occurrence() {
+        let rule = rule("literal:iTunes=>Music");
+
+        assert_eq!(
+            "/Music/Music Mix/a.m4a",
+            rule.apply("/iTunes/iTunes Mix/a.m4a")
+        );
+    }
+
+    #[test]
+    fn rewrites_regex_capture_groups() {
+        let rule = rule("regex:^/data/vinyl-rips/([^/]+)/(.*)$=>Music/$1/Vinyl/$2");
+
+        assert_eq!(
+            "Music/Rush/Vinyl/Moving Pictures/a.flac",
+            rule.apply("/data/vinyl-rips/Rush/Moving Pictures/a.flac")
+        );
+        assert_eq!("/data/other/a.flac", rule.apply("/data/other/a.flac"));
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let rules = vec![
+            rule("prefix:/music/iTunes=>Music"),
+            rule("literal:Music/=>Library/"),
+        ];
+        let reversed: Vec<RewriteRule> = rules.iter().rev().cloned().collect();
+
+        assert_eq!(
+            "Library/Rush/a.m4a",
+            apply_all(&rules, "/music/iTunes/Rush/a.m4a")
+        );
+        assert_eq!(
+            "Music/Rush/a.m4a",
+            apply_all(&reversed, "/music/iTunes/Rush/a.m4a")
+        );
+    }
+
+    #[test]
+    fn round_trips_rules_through_their_text_form() {
+        for value in [
+            "prefix:/music/iTunes=>Music",
+            "literal:iTunes=>Music",
+            "regex:^/a;b/(.*)=>Music/$1",
+        ] {
+            assert_eq!(value, rule(value).to_string());
+        }
+        assert!("prefix:/music".parse::<RewriteRule>().is_err());
+        assert!("other:/music=>Music".parse::<RewriteRule>().is_err());
+        assert!("regex:(=>Music".parse::<RewriteRule>().is_err());
+    }
+}
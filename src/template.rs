@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::m3u::Item;
+
+const FIELDS: [&str; 7] = ["artist", "album", "title", "track", "disc", "year", "ext"];
+const UNKNOWN: &str = "Unknown";
+
+#[derive(Debug, Clone)]
+enum Piece {
+    Literal(String),
+    Field { name: String, width: Option<usize> },
+}
+
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    raw: String,
+    pieces: Vec<Piece>,
+}
+
+impl PathTemplate {
+    pub fn render(&self, fields: &HashMap<&str, Option<String>>) -> String {
+        let mut path = String::new();
+        for piece in self.pieces.iter() {
+            match piece {
+                Piece::Literal(literal) => path.push_str(literal),
+                Piece::Field { name, width } => {
+                    let value = fields
+                        .get(name.as_str())
+                        .cloned()
+                        .flatten()
+                        .filter(|value| !value.is_empty());
+                    let rendered = match (width, value) {
+                        (Some(width), None) => format!("{:0width$}", 0, width = width),
+                        (Some(width), Some(value)) => match value.parse::<u64>() {
+                            Ok(number) => format!("{:0width$}", number, width = width),
+                            Err(_) => value,
+                        },
+                        (None, Some(value)) => value,
+                        (None, None) => UNKNOWN.to_string(),
+                    };
+                    path.push_str(&rendered.replace(['/', '\\'], "_"));
+                }
+            }
+        }
+        path
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut pieces = vec![];
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                pieces.push(Piece::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or(format!("unclosed {{ in template {}", value))?;
+            let field = &rest[start + 1..start + end];
+            let (name, width) = match field.split_once(':') {
+                Some((name, width)) => match width.parse::<usize>() {
+                    Ok(width) => (name, Some(width)),
+                    Err(_) => return Err(format!("invalid width {} in template", width)),
+                },
+                None => (field, None),
+            };
+            if !FIELDS.contains(&name) {
+                return Err(format!(
+                    "unknown field {} in template, expected one of: {}",
+                    name,
+                    FIELDS.join(", ")
+                ));
+            }
+            pieces.push(Piece::Field {
+                name: name.to_string(),
+                width,
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Literal(rest.to_string()));
+        }
+        Ok(PathTemplate {
+            raw: value.to_string(),
+            pieces,
+        })
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+/// Gives each colliding path a ` (n)` suffix. `claimed_paths` maps the key of
+/// each file to the path it was given, so a file keeps its path across runs and
+/// across every playlist sharing it.
+pub fn resolve_collisions(
+    tracks: &mut [(String, Item)],
+    claimed_paths: &mut BTreeMap<String, String>,
+) -> usize {
+    let mut owners: HashMap<String, String> = claimed_paths
+        .iter()
+        .map(|(key, path)| (path.to_lowercase(), key.clone()))
+        .collect();
+    let mut renamed = 0;
+    for (key, track) in tracks.iter_mut() {
+        let path = match claimed_paths.get(key.as_str()) {
+            Some(claimed) if is_variant(claimed, &track.path) => claimed.clone(),
+            _ => {
+                let mut index = 1;
+                let path = loop {
+                    let candidate = variant(&track.path, index);
+                    match owners.get(&candidate.to_lowercase()) {
+                        Some(owner) if owner != key => index += 1,
+                        _ => break candidate,
+                    }
+                };
+                if let Some(previous) = claimed_paths.insert(key.clone(), path.clone()) {
+                    owners.remove(&previous.to_lowercase());
+                }
+                owners.insert(path.to_lowercase(), key.clone());
+                path
+            }
+        };
+        if path != track.path {
+            renamed += 1;
+            track.path = path;
+        }
+    }
+    renamed
+}
+
+fn split_extension(path: &str) -> (&str, &str) {
+    match path.rfind('.') {
+        Some(dot) if !path[dot..].contains(['/', '\\']) => path.split_at(dot),
+        _ => (path, ""),
+    }
+}
+
+fn variant(path: &str, index: usize) -> String {
+    if index == 1 {
+        return path.to_string();
+    }
+    let (stem, extension) = split_extension(path);
+    format!("{} ({}){}", stem, index, extension)
+}
+
+fn is_variant(candidate: &str, path: &str) -> bool {
+    let candidate = candidate.to_lowercase();
+    let path = path.to_lowercase();
+    if candidate == path {
+        return true;
+    }
+    let (stem, extension) = split_extension(&path);
+    candidate
+        .strip_prefix(stem)
+        .and_then(|rest| rest.strip_suffix(extension))
+        .and_then(|rest| rest.strip_prefix(" ("))
+        .and_then(|rest| rest.strip_suffix(')'))
+        .and_then(|index| index.parse::<usize>().ok())
+        .is_some_and(|index| index > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(key: &str, path: &str) -> (String, Item) {
+        (key.to_string(), Item::new(path.to_string(), vec![]))
+    }
+
+    fn paths(tracks: &[(String, Item)]) -> Vec<&str> {
+        tracks
+            .iter()
+            .map(|(_, track)| track.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn renders_fields_with_padding_and_fallbacks() {
+        let template: PathTemplate = "{artist}/{album}/{disc:2}-{track:02} {title}.{ext}"
+            .parse()
+            .unwrap();
+        let fields = HashMap::from([
+            ("artist", Some(String::from("AC/DC"))),
+            ("album", None),
+            ("title", Some(String::from("T.N.T."))),
+            ("track", Some(String::from("3"))),
+            ("disc", None),
+            ("ext", Some(String::from("flac"))),
+        ]);
+
+        assert_eq!("AC_DC/Unknown/00-03 T.N.T..flac", template.render(&fields));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!("{artist".parse::<PathTemplate>().is_err());
+        assert!("{genre}/{title}".parse::<PathTemplate>().is_err());
+        assert!("{track:two}".parse::<PathTemplate>().is_err());
+    }
+
+    #[test]
+    fn suffixes_colliding_paths() {
+        let mut claimed_paths = BTreeMap::new();
+        let mut tracks = vec![
+            track("1/0", "Artist/Song.mp3"),
+            track("2/0", "artist/song.mp3"),
+            track("1/0", "Artist/Song.mp3"),
+            track("3/0", "Artist/Song.mp3"),
+        ];
+
+        assert_eq!(2, resolve_collisions(&mut tracks, &mut claimed_paths));
+        assert_eq!(
+            vec![
+                "Artist/Song.mp3",
+                "artist/song (2).mp3",
+                "Artist/Song.mp3",
+                "Artist/Song (3).mp3"
+            ],
+            paths(&tracks)
+        );
+    }
+
+    #[test]
+    fn keeps_claimed_paths_regardless_of_order() {
+        let mut claimed_paths = BTreeMap::new();
+        let mut first = vec![track("1/0", "Song.mp3"), track("2/0", "Song.mp3")];
+        resolve_collisions(&mut first, &mut claimed_paths);
+
+        let mut second = vec![track("2/0", "Song.mp3"), track("1/0", "Song.mp3")];
+        resolve_collisions(&mut second, &mut claimed_paths);
+
+        assert_eq!(vec!["Song (2).mp3", "Song.mp3"], paths(&second));
+    }
+
+    #[test]
+    fn shares_claims_across_playlists() {
+        let mut claimed_paths = BTreeMap::new();
+        let mut first_playlist = vec![track("1/0", "Song.mp3")];
+        resolve_collisions(&mut first_playlist, &mut claimed_paths);
+
+        let mut second_playlist = vec![track("2/0", "Song.mp3")];
+        resolve_collisions(&mut second_playlist, &mut claimed_paths);
+
+        assert_eq!(vec!["Song (2).mp3"], paths(&second_playlist));
+    }
+
+    #[test]
+    fn claims_a_new_path_when_the_template_changes() {
+        let mut claimed_paths = BTreeMap::new();
+        let mut tracks = vec![track("1/0", "Song.mp3"), track("2/0", "Song.mp3")];
+        resolve_collisions(&mut tracks, &mut claimed_paths);
+
+        let mut tracks = vec![track("2/0", "Other.flac"), track("3/0", "Song (2).mp3")];
+        resolve_collisions(&mut tracks, &mut claimed_paths);
+
+        assert_eq!(vec!["Other.flac", "Song (2).mp3"], paths(&tracks));
+    }
+}